@@ -1,10 +1,107 @@
 use borsh::{BorshDeserialize, BorshSerialize};
-use inindexer::near_indexer_primitives::types::Balance;
+use inindexer::near_indexer_primitives::types::{AccountId, Balance};
 
-#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+/// Storage key prefixes that `aidols.near` has kept pool states under, tried in
+/// order. If the contract moves its pools to a new prefix, add it here so that
+/// states under both are recognized during the migration.
+///
+/// - `0x00`: the only prefix used so far
+pub const POOL_KEY_PREFIXES: &[&[u8]] = &[&[0x00]];
+
+/// Token ID of the pool stored under `key`, from the first of `prefixes` that the
+/// key starts with and is followed by a valid account ID
+pub fn pool_key_token_id(key: &[u8], prefixes: &[&[u8]]) -> Option<AccountId> {
+    prefixes.iter().find_map(|prefix| {
+        let mut without_prefix = key.strip_prefix(*prefix)?;
+        <AccountId as BorshDeserialize>::deserialize(&mut without_prefix).ok()
+    })
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
 pub struct AidolsPoolState {
     pub token_hold: Balance,
     pub wnear_hold: Balance,
     pub is_deployed: bool,
     pub is_tradable: bool,
 }
+
+/// Pool state layouts used by `aidols.near`. When the contract changes its layout,
+/// add the new version here and in [`AidolsPoolState::deserialize_version`].
+///
+/// - 1: `token_hold: u128, wnear_hold: u128, is_deployed: bool, is_tradable: bool`
+pub const KNOWN_VERSIONS: &[u8] = &[1];
+
+/// Spot prices are in yoctoNEAR per this many raw token units
+pub const SPOT_PRICE_PRECISION: u128 = 10u128.pow(24);
+
+impl AidolsPoolState {
+    /// Tries the layout of `preferred_version` first, then all other known versions.
+    /// Returns the state and the version whose layout matched.
+    pub fn deserialize_versioned(bytes: &[u8], preferred_version: u8) -> Option<(Self, u8)> {
+        std::iter::once(preferred_version)
+            .chain(
+                KNOWN_VERSIONS
+                    .iter()
+                    .copied()
+                    .filter(|version| *version != preferred_version),
+            )
+            .find_map(|version| {
+                Self::deserialize_version(bytes, version).map(|state| (state, version))
+            })
+    }
+
+    /// Constant-product price, `wnear_hold / token_hold` scaled by
+    /// [`SPOT_PRICE_PRECISION`]. `None` if the pool holds no tokens.
+    pub fn spot_price_in_wnear(&self) -> Option<u128> {
+        mul_div(self.wnear_hold, SPOT_PRICE_PRECISION, self.token_hold)
+    }
+
+    /// Value of `total_supply` tokens at the spot price, in yoctoNEAR. 0 if the
+    /// pool holds no tokens.
+    pub fn market_cap_near(&self, total_supply: u128) -> u128 {
+        if self.token_hold == 0 {
+            return 0;
+        }
+        mul_div(self.wnear_hold, total_supply, self.token_hold).unwrap_or(u128::MAX)
+    }
+
+    fn deserialize_version(mut bytes: &[u8], version: u8) -> Option<Self> {
+        match version {
+            1 => <Self as BorshDeserialize>::deserialize(&mut bytes).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// `a * b / c` without overflowing the intermediate product. `None` if `c` is 0
+/// or the result doesn't fit in u128.
+pub(crate) fn mul_div(a: u128, b: u128, c: u128) -> Option<u128> {
+    if c == 0 {
+        return None;
+    }
+    const LOW_64: u128 = u64::MAX as u128;
+    let (a_high, a_low) = (a >> 64, a & LOW_64);
+    let (b_high, b_low) = (b >> 64, b & LOW_64);
+    let low_low = a_low * b_low;
+    let low_high = a_low * b_high;
+    let high_low = a_high * b_low;
+    let middle = (low_low >> 64) + (low_high & LOW_64) + (high_low & LOW_64);
+    let product_low = (low_low & LOW_64) | (middle << 64);
+    let product_high = a_high * b_high + (low_high >> 64) + (high_low >> 64) + (middle >> 64);
+    if product_high >= c {
+        return None;
+    }
+    // Long division of the 256-bit product, one bit at a time
+    let mut remainder = product_high;
+    let mut quotient = 0;
+    for bit in (0..128).rev() {
+        let carry = remainder >> 127;
+        remainder = (remainder << 1) | ((product_low >> bit) & 1);
+        quotient <<= 1;
+        if carry == 1 || remainder >= c {
+            remainder = remainder.wrapping_sub(c);
+            quotient |= 1;
+        }
+    }
+    Some(quotient)
+}
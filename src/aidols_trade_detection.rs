@@ -4,6 +4,7 @@ use inindexer::near_utils::EventLogData;
 use inindexer::{
     near_indexer_primitives::{
         types::{AccountId, Balance},
+        views::{ActionView, ReceiptEnumView},
         StreamerMessage,
     },
     near_utils::dec_format,
@@ -11,18 +12,30 @@ use inindexer::{
 };
 use serde::Deserialize;
 
-use crate::{BalanceChangeSwap, PoolId, RawPoolSwap, TradeContext, TradeEventHandler};
+use crate::{
+    deserialize_canonical_account_id, is_valid_trader, BalanceChangeSwap, BridgeContract, PoolId,
+    RawPoolSwap, TradeContext, TradeEventHandler,
+};
 
 pub const AIDOLS_CONTRACT_ID: &str = "aidols.near";
+/// Aidols tokens are deployed as subaccounts of the Aidols contract
+pub const AIDOLS_TOKEN_SUFFIX: &str = ".aidols.near";
+
+// Aidols pools are bonding curves without LP shares, so there's no liquidity
+// detection here. Once a token graduates (`is_deployed` in the pool state), it's
+// traded on a regular DEX, and liquidity of Ref pools is detected in
+// ref_trade_detection.
 
 #[derive(Deserialize, Debug)]
 #[allow(dead_code)]
 struct SwapEvent {
     #[serde(with = "dec_format")]
     input_amount: Balance,
+    #[serde(deserialize_with = "deserialize_canonical_account_id")]
     input_token: AccountId,
     #[serde(with = "dec_format")]
     output_amount: Balance,
+    #[serde(deserialize_with = "deserialize_canonical_account_id")]
     output_token: AccountId,
     refferal_id: Option<AccountId>,
     #[serde(with = "dec_format")]
@@ -34,21 +47,40 @@ struct SwapEvent {
     wnear_hold: Balance,
 }
 
+#[derive(Deserialize, Debug)]
+struct BurnEvent {
+    #[serde(deserialize_with = "deserialize_canonical_account_id")]
+    token_id: AccountId,
+    #[serde(with = "dec_format")]
+    amount: Balance,
+}
+
+/// Returns the pool swaps that were sent to the handler
 pub async fn detect(
     receipt: &TransactionReceipt,
     transaction: &IncompleteTransaction,
     block: &StreamerMessage,
     handler: &mut impl TradeEventHandler,
     is_testnet: bool,
-) {
+    commissions: &mut HashMap<PoolId, Balance>,
+) -> Vec<RawPoolSwap> {
+    // TODO: Aidols has no known testnet deployment. Once there is one, add a
+    // TESTNET_AIDOLS_CONTRACT_ID and select it like ref_trade_detection does
     if is_testnet {
-        return;
+        return Vec::new();
     }
+    let mut raw_pool_swaps = Vec::new();
+    // One token_swap event can contain multiple swaps, they're combined into one
+    // balance change per trader like multi-hop Ref swaps
+    let mut swaps_by_trader: Vec<(TradeContext, Vec<RawPoolSwap>)> = Vec::new();
     if receipt.is_successful(false) && receipt.receipt.receipt.receiver_id == AIDOLS_CONTRACT_ID {
         for log in &receipt.receipt.execution_outcome.outcome.logs {
             if let Ok(event) = EventLogData::<Vec<SwapEvent>>::deserialize(log) {
                 if event.event == "token_swap" {
                     for swap in event.data {
+                        if !is_valid_trader(&swap.user_id, receipt) {
+                            continue;
+                        }
                         let context = TradeContext {
                             trader: swap.user_id.clone(),
                             block_height: block.block.header.height,
@@ -61,41 +93,191 @@ pub async fn detect(
                         } else {
                             swap.input_token.clone()
                         };
+                        let pool_id = create_aidols_pool_id(&token);
+                        // A swap of a token for itself has no net effect and could only be
+                        // a flash loan, so it's flagged and not reported as a balance change
+                        let is_flash_loan = swap.input_token == swap.output_token;
+                        if is_flash_loan {
+                            log::warn!(
+                                "Aidols swap of {} for itself in receipt {}, treating it as a flash loan",
+                                swap.input_token,
+                                receipt.receipt.receipt.receipt_id
+                            );
+                        }
+                        let raw_pool_swap = RawPoolSwap {
+                            pool: if is_flash_loan {
+                                format!("{pool_id}-FLASH")
+                            } else {
+                                pool_id.clone()
+                            },
+                            token_in: swap.input_token.clone(),
+                            token_out: swap.output_token.clone(),
+                            amount_in: swap.input_amount,
+                            amount_out: swap.output_amount,
+                            referral: swap.refferal_id.clone(),
+                            // Aidols only logs the total commission, not the part
+                            // that goes to the referrer
+                            referral_commission: None,
+                            // Commissions are reported through on_commission_collected
+                            fee_breakdown: None,
+                            amount_in_usd: None,
+                            amount_out_usd: None,
+                        };
                         handler
-                            .on_raw_pool_swap(
-                                context.clone(),
-                                RawPoolSwap {
-                                    pool: create_aidols_pool_id(&token),
-                                    token_in: swap.input_token.clone(),
-                                    token_out: swap.output_token.clone(),
-                                    amount_in: swap.input_amount,
-                                    amount_out: swap.output_amount,
-                                },
-                            )
+                            .on_raw_pool_swap(context.clone(), raw_pool_swap.clone())
                             .await;
+                        if !is_flash_loan {
+                            if let Some((_, trader_swaps)) = swaps_by_trader
+                                .iter_mut()
+                                .find(|(trader_context, _)| trader_context.trader == swap.user_id)
+                            {
+                                trader_swaps.push(raw_pool_swap.clone());
+                            } else {
+                                swaps_by_trader
+                                    .push((context.clone(), vec![raw_pool_swap.clone()]));
+                            }
+                        }
+                        if swap.wnear_commission != 0 {
+                            let total_commission = commissions.entry(pool_id.clone()).or_insert(0);
+                            *total_commission += swap.wnear_commission;
+                            let total_commission = *total_commission;
+                            handler
+                                .on_commission_collected(
+                                    context,
+                                    pool_id,
+                                    swap.wnear_commission,
+                                    total_commission,
+                                )
+                                .await;
+                        }
+                        raw_pool_swaps.push(raw_pool_swap);
+                    }
+                }
+            }
+            if let Ok(event) = EventLogData::<Vec<BurnEvent>>::deserialize(log) {
+                if event.event == "token_burned" {
+                    let burner = &receipt.receipt.receipt.predecessor_id;
+                    if !is_valid_trader(burner, receipt) {
+                        continue;
+                    }
+                    for burn in event.data {
+                        let context = TradeContext {
+                            trader: burner.clone(),
+                            block_height: block.block.header.height,
+                            block_timestamp_nanosec: block.block.header.timestamp_nanosec as u128,
+                            transaction_id: transaction.transaction.transaction.hash,
+                            receipt_id: receipt.receipt.receipt.receipt_id,
+                        };
                         handler
-                            .on_balance_change_swap(
-                                context,
-                                BalanceChangeSwap {
-                                    balance_changes: HashMap::from_iter([
-                                        (swap.input_token.clone(), -(swap.input_amount as i128)),
-                                        (swap.output_token.clone(), swap.output_amount as i128),
-                                    ]),
-                                    pool_swaps: vec![RawPoolSwap {
-                                        pool: create_aidols_pool_id(&token),
-                                        token_in: swap.input_token.clone(),
-                                        token_out: swap.output_token.clone(),
-                                        amount_in: swap.input_amount,
-                                        amount_out: swap.output_amount,
-                                    }],
-                                },
-                            )
+                            .on_token_burn(context, burn.token_id, burn.amount, burner.clone())
                             .await;
                     }
                 }
             }
         }
     }
+    for (context, pool_swaps) in swaps_by_trader {
+        let balance_changes = net_balance_changes(&pool_swaps);
+        if !balance_changes.is_empty() {
+            handler
+                .on_balance_change_swap(
+                    context,
+                    BalanceChangeSwap {
+                        balance_changes,
+                        pool_swaps,
+                    },
+                )
+                .await;
+        }
+    }
+    raw_pool_swaps
+}
+
+/// `ft_transfer_call`s of Aidols tokens to one of `bridge_contracts`
+pub async fn detect_bridge_transfers(
+    receipt: &TransactionReceipt,
+    transaction: &IncompleteTransaction,
+    block: &StreamerMessage,
+    handler: &mut impl TradeEventHandler,
+    is_testnet: bool,
+    bridge_contracts: &[BridgeContract],
+) {
+    let token = &receipt.receipt.receipt.receiver_id;
+    if is_testnet || !receipt.is_successful(false) || !token.as_str().ends_with(AIDOLS_TOKEN_SUFFIX)
+    {
+        return;
+    }
+    let ReceiptEnumView::Action { actions, .. } = &receipt.receipt.receipt.receipt else {
+        return;
+    };
+    let trader = &receipt.receipt.receipt.predecessor_id;
+    for action in actions {
+        let ActionView::FunctionCall {
+            method_name, args, ..
+        } = action
+        else {
+            continue;
+        };
+        if method_name != "ft_transfer_call" {
+            continue;
+        }
+        let Ok(call) = serde_json::from_slice::<FtTransferCall>(args) else {
+            continue;
+        };
+        let Some(bridge) = bridge_contracts
+            .iter()
+            .find(|bridge| call.receiver_id == bridge.account_id())
+        else {
+            continue;
+        };
+        let Some(destination_address) = bridge.destination_address(&call.msg) else {
+            log::warn!(
+                "Invalid {} bridge message in receipt {}: {:?}",
+                bridge.chain(),
+                receipt.receipt.receipt.receipt_id,
+                call.msg
+            );
+            continue;
+        };
+        if !is_valid_trader(trader, receipt) {
+            continue;
+        }
+        handler
+            .on_cross_chain_swap(
+                trader.clone(),
+                token.clone(),
+                call.amount,
+                bridge.chain().to_owned(),
+                destination_address,
+                TradeContext {
+                    trader: trader.clone(),
+                    block_height: block.block.header.height,
+                    block_timestamp_nanosec: block.block.header.timestamp_nanosec as u128,
+                    transaction_id: transaction.transaction.transaction.hash,
+                    receipt_id: receipt.receipt.receipt.receipt_id,
+                },
+            )
+            .await;
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct FtTransferCall {
+    receiver_id: AccountId,
+    #[serde(with = "dec_format")]
+    amount: Balance,
+    msg: String,
+}
+
+/// Net change of each token over all swaps, tokens with no net change are omitted
+pub(crate) fn net_balance_changes(swaps: &[RawPoolSwap]) -> HashMap<AccountId, i128> {
+    let mut balance_changes = HashMap::new();
+    for swap in swaps {
+        *balance_changes.entry(swap.token_in.clone()).or_insert(0) -= swap.amount_in as i128;
+        *balance_changes.entry(swap.token_out.clone()).or_insert(0) += swap.amount_out as i128;
+    }
+    balance_changes.retain(|_, change| *change != 0);
+    balance_changes
 }
 
 pub fn create_aidols_pool_id(token_id: &AccountId) -> PoolId {
@@ -0,0 +1,41 @@
+use std::future::Future;
+#[cfg(debug_assertions)]
+use std::{collections::HashMap, time::Duration, time::Instant};
+
+use inindexer::near_indexer_primitives::types::BlockHeight;
+
+/// Time spent in each detection module during the current block. Only measured
+/// in debug builds, in release builds this is empty and does nothing.
+#[derive(Debug, Default)]
+pub(crate) struct DetectionTimes {
+    #[cfg(debug_assertions)]
+    times: HashMap<&'static str, Duration>,
+}
+
+impl DetectionTimes {
+    #[cfg_attr(not(debug_assertions), allow(unused_variables))]
+    pub async fn measure<F: Future>(&mut self, module: &'static str, detection: F) -> F::Output {
+        #[cfg(debug_assertions)]
+        let started_at = Instant::now();
+        let output = detection.await;
+        #[cfg(debug_assertions)]
+        {
+            let elapsed = started_at.elapsed();
+            log::trace!("{module} detection took {elapsed:?}");
+            *self.times.entry(module).or_default() += elapsed;
+        }
+        output
+    }
+
+    /// Logs the module that took the most time in the block and resets the times
+    #[cfg_attr(not(debug_assertions), allow(unused_variables))]
+    pub fn finish_block(&mut self, block_height: BlockHeight) {
+        #[cfg(debug_assertions)]
+        if let Some((module, time)) = std::mem::take(&mut self.times)
+            .into_iter()
+            .max_by_key(|(_, time)| *time)
+        {
+            log::debug!("Slowest detection module in block {block_height}: {module} ({time:?})");
+        }
+    }
+}
@@ -0,0 +1,395 @@
+use std::collections::HashMap;
+
+use crate::meme_cooking_deposit_detection::{DepositEvent, WithdrawEvent};
+#[cfg(feature = "rpc")]
+use crate::metadata_cache::FtMetadata;
+use crate::orderly_transfer_detection::ProtocolTransferEvent;
+use crate::{
+    AidolsPoolDiff, BalanceChangeSwap, PoolChangeEvent, PoolId, PoolTvlEstimate, RawPoolSwap,
+    SwapGasStats, TradeContext, TradeEventHandler,
+};
+use async_trait::async_trait;
+use inindexer::near_indexer_primitives::types::{AccountId, Balance, BlockHeight};
+
+const DRAGONBOT_ACCOUNT_SUFFIX: &str = ".dragon_bot.near";
+
+/// Dragonbot v2 splits one trade into several `ft_transfer_call`s in the same
+/// transaction. This handler merges the balance change swaps of a dragonbot
+/// account in one transaction into a single event, sent when the block is
+/// flushed. Everything else is passed through to `inner` unchanged.
+///
+/// Only swaps of one transaction that land in the same block are merged. If its
+/// `ft_transfer_call`s execute in different blocks, each block's swaps are sent as
+/// a separate event, since a handler can't tell whether a transaction has more
+/// receipts to come.
+pub struct GroupDragonbotSwaps<T: TradeEventHandler> {
+    pub inner: T,
+    pending: Vec<(TradeContext, BalanceChangeSwap)>,
+}
+
+impl<T: TradeEventHandler> GroupDragonbotSwaps<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            pending: Vec::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl<T: TradeEventHandler> TradeEventHandler for GroupDragonbotSwaps<T> {
+    async fn on_raw_pool_swap(&mut self, context: TradeContext, swap: RawPoolSwap) {
+        self.inner.on_raw_pool_swap(context, swap).await;
+    }
+
+    async fn on_balance_change_swap(
+        &mut self,
+        context: TradeContext,
+        balance_changes: BalanceChangeSwap,
+    ) {
+        if !context.trader.as_str().ends_with(DRAGONBOT_ACCOUNT_SUFFIX) {
+            self.inner
+                .on_balance_change_swap(context, balance_changes)
+                .await;
+            return;
+        }
+        if let Some((_, grouped)) = self.pending.iter_mut().find(|(pending_context, _)| {
+            pending_context.transaction_id == context.transaction_id
+                && pending_context.trader == context.trader
+        }) {
+            for (token, change) in balance_changes.balance_changes {
+                *grouped.balance_changes.entry(token).or_insert(0) += change;
+            }
+            grouped.pool_swaps.extend(balance_changes.pool_swaps);
+        } else {
+            self.pending.push((context, balance_changes));
+        }
+    }
+
+    async fn on_pool_change(&mut self, pool: PoolChangeEvent) {
+        self.inner.on_pool_change(pool).await;
+    }
+
+    async fn on_bulk_pool_update(&mut self, events: Vec<PoolChangeEvent>) {
+        self.inner.on_bulk_pool_update(events).await;
+    }
+
+    async fn on_memecooking_deposit(&mut self, context: TradeContext, deposit: DepositEvent) {
+        self.inner.on_memecooking_deposit(context, deposit).await;
+    }
+
+    async fn on_memecooking_withdraw(&mut self, context: TradeContext, withdraw: WithdrawEvent) {
+        self.inner.on_memecooking_withdraw(context, withdraw).await;
+    }
+
+    async fn on_liquidity_pool(
+        &mut self,
+        context: TradeContext,
+        pool_id: PoolId,
+        tokens: HashMap<AccountId, i128>,
+    ) {
+        self.inner.on_liquidity_pool(context, pool_id, tokens).await;
+    }
+
+    async fn on_amp_ramp_started(
+        &mut self,
+        pool_id: PoolId,
+        from_amp: u128,
+        to_amp: u128,
+        ramp_end_time: u64,
+    ) {
+        self.inner
+            .on_amp_ramp_started(pool_id, from_amp, to_amp, ramp_end_time)
+            .await;
+    }
+
+    async fn on_commission_collected(
+        &mut self,
+        context: TradeContext,
+        pool_id: PoolId,
+        commission: Balance,
+        total_commission: Balance,
+    ) {
+        self.inner
+            .on_commission_collected(context, pool_id, commission, total_commission)
+            .await;
+    }
+
+    async fn on_output_redirected(&mut self, context: TradeContext, output_account: AccountId) {
+        self.inner
+            .on_output_redirected(context, output_account)
+            .await;
+    }
+
+    async fn on_lp_share_transfer(
+        &mut self,
+        context: TradeContext,
+        pool_id: PoolId,
+        sender: AccountId,
+        receiver: AccountId,
+        amount: Balance,
+    ) {
+        self.inner
+            .on_lp_share_transfer(context, pool_id, sender, receiver, amount)
+            .await;
+    }
+
+    async fn on_storage_withdrawn(
+        &mut self,
+        context: TradeContext,
+        account_id: AccountId,
+        amount: Balance,
+    ) {
+        self.inner
+            .on_storage_withdrawn(context, account_id, amount)
+            .await;
+    }
+
+    async fn on_account_unregistered(&mut self, context: TradeContext, account_id: AccountId) {
+        self.inner
+            .on_account_unregistered(context, account_id)
+            .await;
+    }
+
+    async fn on_funding_payment(
+        &mut self,
+        context: TradeContext,
+        token: AccountId,
+        amount: i128,
+        market_id: String,
+    ) {
+        self.inner
+            .on_funding_payment(context, token, amount, market_id)
+            .await;
+    }
+
+    async fn on_farm_seed_withdrawn(
+        &mut self,
+        context: TradeContext,
+        pool_id: PoolId,
+        seed_amount: Balance,
+        trader: AccountId,
+    ) {
+        self.inner
+            .on_farm_seed_withdrawn(context, pool_id, seed_amount, trader)
+            .await;
+    }
+
+    async fn on_flash_swap(&mut self, context: TradeContext, balance_changes: BalanceChangeSwap) {
+        self.inner.on_flash_swap(context, balance_changes).await;
+    }
+
+    async fn on_pool_utilization(
+        &mut self,
+        pool_id: PoolId,
+        utilization_bps: u32,
+        block_height: BlockHeight,
+    ) {
+        self.inner
+            .on_pool_utilization(pool_id, utilization_bps, block_height)
+            .await;
+    }
+
+    async fn on_pool_swap_stats(
+        &mut self,
+        pool_id: PoolId,
+        success_rate: f32,
+        block_height: BlockHeight,
+    ) {
+        self.inner
+            .on_pool_swap_stats(pool_id, success_rate, block_height)
+            .await;
+    }
+
+    async fn on_pool_frozen(&mut self, pool_id: PoolId, context: TradeContext) {
+        self.inner.on_pool_frozen(pool_id, context).await;
+    }
+
+    async fn on_pool_unfrozen(&mut self, pool_id: PoolId, context: TradeContext) {
+        self.inner.on_pool_unfrozen(pool_id, context).await;
+    }
+
+    async fn on_withdrawal_fee_charged(
+        &mut self,
+        pool_id: PoolId,
+        fee_token: AccountId,
+        fee_amount: Balance,
+        context: TradeContext,
+    ) {
+        self.inner
+            .on_withdrawal_fee_charged(pool_id, fee_token, fee_amount, context)
+            .await;
+    }
+
+    async fn on_ref_deposit(
+        &mut self,
+        trader: AccountId,
+        token: AccountId,
+        amount: Balance,
+        context: TradeContext,
+    ) {
+        self.inner
+            .on_ref_deposit(trader, token, amount, context)
+            .await;
+    }
+
+    async fn on_ref_withdraw(
+        &mut self,
+        trader: AccountId,
+        token: AccountId,
+        amount: Balance,
+        context: TradeContext,
+    ) {
+        self.inner
+            .on_ref_withdraw(trader, token, amount, context)
+            .await;
+    }
+
+    async fn on_deposit_refunded(
+        &mut self,
+        trader: AccountId,
+        token: AccountId,
+        refunded_amount: Balance,
+        context: TradeContext,
+    ) {
+        self.inner
+            .on_deposit_refunded(trader, token, refunded_amount, context)
+            .await;
+    }
+
+    async fn on_cross_chain_swap(
+        &mut self,
+        trader: AccountId,
+        token: AccountId,
+        amount: Balance,
+        destination_chain: String,
+        destination_address: String,
+        context: TradeContext,
+    ) {
+        self.inner
+            .on_cross_chain_swap(
+                trader,
+                token,
+                amount,
+                destination_chain,
+                destination_address,
+                context,
+            )
+            .await;
+    }
+
+    async fn on_guardian_changed(
+        &mut self,
+        old_guardian: Option<AccountId>,
+        new_guardian: Option<AccountId>,
+        context: TradeContext,
+    ) {
+        self.inner
+            .on_guardian_changed(old_guardian, new_guardian, context)
+            .await;
+    }
+
+    async fn on_swap_gas_stats(&mut self, context: TradeContext, stats: SwapGasStats) {
+        self.inner.on_swap_gas_stats(context, stats).await;
+    }
+
+    async fn on_aidols_pool_diff(
+        &mut self,
+        pool_id: PoolId,
+        diff: AidolsPoolDiff,
+        context: TradeContext,
+    ) {
+        self.inner.on_aidols_pool_diff(pool_id, diff, context).await;
+    }
+
+    async fn on_owner_changed(
+        &mut self,
+        old_owner: AccountId,
+        new_owner: AccountId,
+        context: TradeContext,
+    ) {
+        self.inner
+            .on_owner_changed(old_owner, new_owner, context)
+            .await;
+    }
+
+    async fn on_tvl_estimate(&mut self, estimate: PoolTvlEstimate) {
+        self.inner.on_tvl_estimate(estimate).await;
+    }
+
+    async fn on_token_pressure(
+        &mut self,
+        token: AccountId,
+        buy_pressure: Balance,
+        sell_pressure: Balance,
+        block_height: BlockHeight,
+    ) {
+        self.inner
+            .on_token_pressure(token, buy_pressure, sell_pressure, block_height)
+            .await;
+    }
+
+    async fn on_token_burn(
+        &mut self,
+        context: TradeContext,
+        token: AccountId,
+        amount: Balance,
+        burner: AccountId,
+    ) {
+        self.inner
+            .on_token_burn(context, token, amount, burner)
+            .await;
+    }
+
+    #[cfg(feature = "rpc")]
+    async fn on_new_token(&mut self, token: AccountId, metadata: FtMetadata) {
+        self.inner.on_new_token(token, metadata).await;
+    }
+
+    async fn on_deposit_to_protocol(
+        &mut self,
+        context: TradeContext,
+        transfer: ProtocolTransferEvent,
+    ) {
+        self.inner.on_deposit_to_protocol(context, transfer).await;
+    }
+
+    async fn on_withdrawal_from_protocol(
+        &mut self,
+        context: TradeContext,
+        transfer: ProtocolTransferEvent,
+    ) {
+        self.inner
+            .on_withdrawal_from_protocol(context, transfer)
+            .await;
+    }
+
+    async fn on_social_post(&mut self, context: TradeContext, content: String) {
+        self.inner.on_social_post(context, content).await;
+    }
+
+    async fn on_social_trade_post(
+        &mut self,
+        trader: AccountId,
+        post_content: String,
+        swap: BalanceChangeSwap,
+    ) {
+        self.inner
+            .on_social_trade_post(trader, post_content, swap)
+            .await;
+    }
+
+    async fn flush_events(&mut self, block_height: BlockHeight) {
+        for (context, mut balance_changes) in std::mem::take(&mut self.pending) {
+            balance_changes
+                .balance_changes
+                .retain(|_, change| *change != 0);
+            if !balance_changes.balance_changes.is_empty() {
+                self.inner
+                    .on_balance_change_swap(context, balance_changes)
+                    .await;
+            }
+        }
+        self.inner.flush_events(block_height).await;
+    }
+}
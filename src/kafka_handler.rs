@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+
+use crate::meme_cooking_deposit_detection::{DepositEvent, WithdrawEvent};
+use crate::redis_handler::trade_pool_change_event;
+use crate::{
+    BalanceChangeSwap, PoolChangeEvent, PoolId, RawPoolSwap, TradeContext, TradeEventHandler,
+};
+use async_trait::async_trait;
+use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
+use inindexer::near_indexer_primitives::CryptoHash;
+use intear_events::events::trade::liquidity_pool::LiquidityPoolEvent;
+use intear_events::events::trade::memecooking_deposit::MemeCookingDepositEvent;
+use intear_events::events::trade::memecooking_withdraw::MemeCookingWithdrawEvent;
+use intear_events::events::trade::trade_pool::TradePoolEvent;
+use intear_events::events::trade::trade_swap::TradeSwapEvent;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::ClientConfig;
+use serde::Serialize;
+
+const POOL_SWAPS_TOPIC: &str = "trade.pool_swaps";
+const BALANCE_CHANGES_TOPIC: &str = "trade.balance_changes";
+const POOL_CHANGES_TOPIC: &str = "trade.pool_changes";
+const LIQUIDITY_TOPIC: &str = "trade.liquidity";
+const MEMECOOKING_DEPOSITS_TOPIC: &str = "trade.memecooking_deposits";
+const MEMECOOKING_WITHDRAWALS_TOPIC: &str = "trade.memecooking_withdrawals";
+
+/// Publishes events as JSON to one Kafka topic per event type, keyed by receipt
+/// ID. Messages are buffered and sent when the block is flushed.
+pub struct KafkaHandler {
+    producer: FutureProducer,
+    topic_prefix: String,
+    pending: Vec<PendingMessage>,
+}
+
+struct PendingMessage {
+    topic: &'static str,
+    key: String,
+    payload: String,
+}
+
+impl KafkaHandler {
+    /// `topic_prefix` is prepended as-is to every topic name
+    pub fn new(producer: FutureProducer, topic_prefix: String) -> Self {
+        Self {
+            producer,
+            topic_prefix,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Reads `$KAFKA_BROKERS` (comma-separated `host:port` list), optional
+    /// `$KAFKA_TOPIC_PREFIX`, and optional `$KAFKA_SASL_CONFIG`, a comma-separated
+    /// list of librdkafka properties such as
+    /// `security.protocol=SASL_SSL,sasl.mechanisms=PLAIN,sasl.username=user,sasl.password=pass`
+    pub fn from_env() -> Self {
+        let brokers =
+            std::env::var("KAFKA_BROKERS").expect("No $KAFKA_BROKERS environment variable set");
+        let topic_prefix = std::env::var("KAFKA_TOPIC_PREFIX").unwrap_or_default();
+        let mut config = ClientConfig::new();
+        config.set("bootstrap.servers", brokers);
+        if let Ok(sasl_config) = std::env::var("KAFKA_SASL_CONFIG") {
+            for property in sasl_config.split(',').filter(|p| !p.is_empty()) {
+                let (key, value) = property
+                    .split_once('=')
+                    .expect("Invalid $KAFKA_SASL_CONFIG, expected key=value pairs");
+                config.set(key.trim(), value.trim());
+            }
+        }
+        let producer = config.create().expect("Failed to create Kafka producer");
+        Self::new(producer, topic_prefix)
+    }
+
+    fn add_event(&mut self, topic: &'static str, receipt_id: CryptoHash, event: &impl Serialize) {
+        self.pending.push(PendingMessage {
+            topic,
+            key: receipt_id.to_string(),
+            payload: serde_json::to_string(event).expect("Failed to serialize event"),
+        });
+    }
+}
+
+#[async_trait]
+impl TradeEventHandler for KafkaHandler {
+    async fn on_raw_pool_swap(&mut self, context: TradeContext, swap: RawPoolSwap) {
+        self.add_event(
+            POOL_SWAPS_TOPIC,
+            context.receipt_id,
+            &TradePoolEvent {
+                pool: swap.pool,
+                token_in: swap.token_in,
+                token_out: swap.token_out,
+                amount_in: swap.amount_in,
+                amount_out: swap.amount_out,
+                trader: context.trader,
+                block_height: context.block_height,
+                block_timestamp_nanosec: context.block_timestamp_nanosec,
+                transaction_id: context.transaction_id,
+                receipt_id: context.receipt_id,
+            },
+        );
+    }
+
+    async fn on_balance_change_swap(
+        &mut self,
+        context: TradeContext,
+        balance_changes: BalanceChangeSwap,
+    ) {
+        self.add_event(
+            BALANCE_CHANGES_TOPIC,
+            context.receipt_id,
+            &TradeSwapEvent {
+                balance_changes: balance_changes.balance_changes,
+                trader: context.trader,
+                block_height: context.block_height,
+                block_timestamp_nanosec: context.block_timestamp_nanosec,
+                transaction_id: context.transaction_id,
+                receipt_id: context.receipt_id,
+            },
+        );
+    }
+
+    async fn on_pool_change(&mut self, event: PoolChangeEvent) {
+        let receipt_id = event.receipt_id;
+        if let Some(event) = trade_pool_change_event(event) {
+            self.add_event(POOL_CHANGES_TOPIC, receipt_id, &event);
+        }
+    }
+
+    async fn on_memecooking_deposit(&mut self, context: TradeContext, deposit: DepositEvent) {
+        self.add_event(
+            MEMECOOKING_DEPOSITS_TOPIC,
+            context.receipt_id,
+            &MemeCookingDepositEvent {
+                meme_id: deposit.meme_id,
+                amount: deposit.amount,
+                protocol_fee: deposit.protocol_fee,
+                referrer: deposit.referrer,
+                referrer_fee: deposit.referrer_fee,
+                trader: context.trader,
+                block_height: context.block_height,
+                block_timestamp_nanosec: context.block_timestamp_nanosec,
+                transaction_id: context.transaction_id,
+                receipt_id: context.receipt_id,
+            },
+        );
+    }
+
+    async fn on_memecooking_withdraw(&mut self, context: TradeContext, withdraw: WithdrawEvent) {
+        self.add_event(
+            MEMECOOKING_WITHDRAWALS_TOPIC,
+            context.receipt_id,
+            &MemeCookingWithdrawEvent {
+                meme_id: withdraw.meme_id,
+                amount: withdraw.amount,
+                fee: withdraw.fee,
+                trader: context.trader,
+                block_height: context.block_height,
+                block_timestamp_nanosec: context.block_timestamp_nanosec,
+                transaction_id: context.transaction_id,
+                receipt_id: context.receipt_id,
+            },
+        );
+    }
+
+    async fn on_liquidity_pool(
+        &mut self,
+        context: TradeContext,
+        pool_id: PoolId,
+        tokens: HashMap<AccountId, i128>,
+    ) {
+        self.add_event(
+            LIQUIDITY_TOPIC,
+            context.receipt_id,
+            &LiquidityPoolEvent {
+                pool: pool_id,
+                tokens,
+                provider_account_id: context.trader,
+                block_height: context.block_height,
+                block_timestamp_nanosec: context.block_timestamp_nanosec,
+                transaction_id: context.transaction_id,
+                receipt_id: context.receipt_id,
+            },
+        );
+    }
+
+    async fn flush_events(&mut self, block_height: BlockHeight) {
+        // Queue everything first so the producer can batch the block's messages
+        let mut deliveries = Vec::with_capacity(self.pending.len());
+        for message in self.pending.drain(..) {
+            let topic = format!("{}{}", self.topic_prefix, message.topic);
+            let record = FutureRecord::to(&topic)
+                .key(&message.key)
+                .payload(&message.payload);
+            match self.producer.send_result(record) {
+                Ok(delivery) => deliveries.push(delivery),
+                Err((err, _)) => {
+                    panic!("Failed to queue Kafka message in block {block_height}: {err}")
+                }
+            }
+        }
+        for delivery in deliveries {
+            match delivery.await {
+                Ok(Ok(_)) => {}
+                Ok(Err((err, _))) => {
+                    panic!("Failed to deliver Kafka message in block {block_height}: {err}")
+                }
+                Err(_) => panic!("Kafka producer dropped a message in block {block_height}"),
+            }
+        }
+    }
+}
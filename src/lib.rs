@@ -1,4 +1,7 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+#[cfg(feature = "rpc")]
+use std::sync::Arc;
+use std::time::Instant;
 
 use aidols_trade_detection::AIDOLS_CONTRACT_ID;
 use async_trait::async_trait;
@@ -12,27 +15,461 @@ use inindexer::{
     IncompleteTransaction, Indexer, TransactionReceipt,
 };
 use intear_events::events::trade::trade_pool_change::AidolsPool;
+use ref_finance_state::{AmpRamp, PoolFees, SwapVolume, FEE_DIVISOR};
 use ref_trade_detection::REF_CONTRACT_ID;
 use ref_trade_detection::TESTNET_REF_CONTRACT_ID;
+#[cfg(feature = "rpc")]
+use tokio::sync::RwLock;
 
+use crate::aidols_state::AidolsPoolState;
+use crate::detection_timing::DetectionTimes;
 use crate::meme_cooking_deposit_detection::{DepositEvent, WithdrawEvent};
+#[cfg(feature = "rpc")]
+use crate::metadata_cache::{FtMetadata, MetadataCache};
+use crate::noop_handler::NoopHandler;
+use crate::orderly_transfer_detection::ProtocolTransferEvent;
+use crate::token_validation::TokenValidator;
+use crate::usd_oracle::{OracleMode, UsdOracle};
 
 mod aidols_state;
 mod aidols_trade_detection;
+mod detection_timing;
+pub mod dragonbot_grouping;
+#[cfg(feature = "kafka")]
+pub mod kafka_handler;
 mod meme_cooking_deposit_detection;
+#[cfg(feature = "rpc")]
+pub mod metadata_cache;
+#[cfg(feature = "nats")]
+pub mod nats_handler;
+pub mod noop_handler;
+mod orderly_transfer_detection;
+pub mod pool_id_utils;
 pub mod redis_handler;
 mod ref_finance_state;
 mod ref_trade_detection;
+pub mod social_correlation;
+mod social_post_detection;
 #[cfg(test)]
 mod tests;
+pub mod token_validation;
+pub mod usd_oracle;
 
 type PoolId = String;
 
+/// How many recent receipt IDs are kept to detect receipts delivered twice
+const MAX_REMEMBERED_RECEIPTS: usize = 100_000;
+pub const LATENCY_WINDOW_BLOCKS: usize = 100;
+const KNOWN_AGGREGATOR_CONTRACTS: &[&str] = &["rhino.fi", "solver.rhino.fi"];
+const KNOWN_RELAYERS: &[(&str, u8)] = &[("ref.hot.tg", 2)];
+const DEFAULT_MAX_POOL_ID: u64 = 1_000_000;
+/// Token ID of native NEAR in balance changes and protocol deposits
+pub const NATIVE_NEAR_TOKEN_ID: &str = "near";
+
 pub struct TradeIndexer<T: TradeEventHandler> {
     pub handler: T,
     pub is_testnet: bool,
+    /// Contracts whose state changes are parsed, so that each state change needs
+    /// one lookup no matter how many DEXes are supported
+    state_contracts: HashMap<AccountId, StateContract>,
+    processed_receipts: ProcessedReceipts,
+    aidols_commissions: HashMap<PoolId, Balance>,
+    amp_ramps: HashMap<PoolId, AmpRamp>,
+    /// Last Aidols pool state layout that matched, tried first for new states
+    aidols_state_version: u8,
+    /// Contracts that swap on Ref on behalf of users, trades are attributed to the
+    /// account that called the aggregator
+    aggregator_contracts: HashSet<AccountId>,
+    /// Contracts that relay user transactions to Ref, with how many receipts up from
+    /// the swap the user's receipt is
+    relayer_depths: HashMap<AccountId, u8>,
+    emit_after_block_height: Option<BlockHeight>,
+    /// Ref pool changes with a higher pool ID are skipped, such IDs usually mean the
+    /// storage key was misparsed. Needs to be raised if Ref actually gets this many
+    /// pools.
+    max_pool_id: u64,
+    /// Only the last change of each pool in a block is sent to the handler
+    coalesce_pool_changes_per_block: bool,
+    /// Share of pool changes to emit for each pool ID, or for each pool ID prefix if
+    /// the key ends with `*`. Pools that aren't listed are always emitted.
+    sampling_rate: HashMap<PoolId, f32>,
+    /// Last known fees of each Ref pool, `None` unless enabled with
+    /// [`TradeIndexer::with_fee_breakdown`]
+    pool_fees: Option<HashMap<PoolId, PoolFees>>,
+    stats: IndexerStats,
+    /// Blocks up to this height are skipped, in case the provider delivers the last
+    /// block again after a restart
+    last_processed_block: Option<BlockHeight>,
+    /// Last known volumes of each Ref pool
+    pool_volumes: HashMap<PoolId, Vec<SwapVolume>>,
+    /// Volumes of Ref pools changed in the current block, checked against
+    /// `pool_volumes` at the end of the block
+    block_pool_volumes: Vec<(PoolId, Vec<SwapVolume>)>,
+    block_started_at: Option<Instant>,
+    /// Token reserves of pools changed in the current block
+    block_pool_reserves: HashMap<PoolId, HashMap<AccountId, Balance>>,
+    /// Amount of each token swapped into each pool in the current block
+    block_pool_swap_amounts: HashMap<PoolId, HashMap<AccountId, Balance>>,
+    /// (successful, total) Ref swaps in each pool in the current block
+    block_pool_swap_counts: HashMap<PoolId, (u64, u64)>,
+    /// Ref pools where the last swap failed because the pool is paused
+    frozen_pools: HashSet<PoolId>,
+    /// State of Ref stable and rated pools right after each receipt in the current
+    /// block that changed them
+    block_stable_pools: HashMap<CryptoHash, (PoolId, ref_finance_state::Pool)>,
+    /// (buy pressure, sell pressure) of each token traded in the current block
+    block_token_pressure: HashMap<AccountId, (Balance, Balance)>,
+    /// Last known state of each Aidols pool
+    aidols_pool_states: HashMap<PoolId, AidolsPoolState>,
+    /// State of Aidols pools changed in the current block before the block, with
+    /// the last receipt that changed them and its context once the receipt is seen
+    block_aidols_pools: HashMap<PoolId, (AidolsPoolState, CryptoHash, Option<TradeContext>)>,
+    /// Time from `process_block` to the end of `process_block_end` of the last
+    /// [`LATENCY_WINDOW_BLOCKS`] blocks
+    block_latencies_ns: VecDeque<u64>,
+    detection_times: DetectionTimes,
+    /// Aidols token transfers to these contracts are reported as cross-chain swaps
+    bridge_contracts: Vec<BridgeContract>,
+    /// Ref simple pools with a token that this rejects are skipped
+    token_validator: Option<Box<dyn TokenValidator>>,
+    usd_oracle: Option<UsdOracle>,
+    #[cfg(feature = "rpc")]
+    metadata_cache: Option<Arc<RwLock<MetadataCache>>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StateContract {
+    Ref,
+    Aidols,
+}
+
+/// Counters of what the indexer has seen since it started, keyed by DEX name
+/// (`"ref"` or `"aidols"`)
+#[derive(Debug, Clone, Default)]
+pub struct IndexerStats {
+    pub swaps_by_dex: HashMap<&'static str, u64>,
+    pub pool_changes_by_dex: HashMap<&'static str, u64>,
+    pub total_blocks_processed: u64,
+    pub total_receipts_processed: u64,
+}
+
+impl<T: TradeEventHandler> TradeIndexer<T> {
+    pub fn new(handler: T, is_testnet: bool) -> Self {
+        let ref_contract_id = if is_testnet {
+            TESTNET_REF_CONTRACT_ID
+        } else {
+            REF_CONTRACT_ID
+        };
+        Self {
+            handler,
+            is_testnet,
+            state_contracts: HashMap::from_iter([
+                (ref_contract_id.parse().unwrap(), StateContract::Ref),
+                (AIDOLS_CONTRACT_ID.parse().unwrap(), StateContract::Aidols),
+            ]),
+            processed_receipts: ProcessedReceipts::new(MAX_REMEMBERED_RECEIPTS),
+            aidols_commissions: HashMap::new(),
+            amp_ramps: HashMap::new(),
+            aidols_state_version: 1,
+            aggregator_contracts: KNOWN_AGGREGATOR_CONTRACTS
+                .iter()
+                .map(|account_id| account_id.parse().unwrap())
+                .collect(),
+            relayer_depths: KNOWN_RELAYERS
+                .iter()
+                .map(|(account_id, depth)| (account_id.parse().unwrap(), *depth))
+                .collect(),
+            emit_after_block_height: None,
+            max_pool_id: DEFAULT_MAX_POOL_ID,
+            coalesce_pool_changes_per_block: false,
+            sampling_rate: HashMap::new(),
+            pool_fees: None,
+            stats: IndexerStats::default(),
+            last_processed_block: None,
+            pool_volumes: HashMap::new(),
+            block_pool_volumes: Vec::new(),
+            block_started_at: None,
+            block_pool_reserves: HashMap::new(),
+            block_pool_swap_amounts: HashMap::new(),
+            block_pool_swap_counts: HashMap::new(),
+            frozen_pools: HashSet::new(),
+            block_stable_pools: HashMap::new(),
+            aidols_pool_states: HashMap::new(),
+            block_aidols_pools: HashMap::new(),
+            block_token_pressure: HashMap::new(),
+            block_latencies_ns: VecDeque::with_capacity(LATENCY_WINDOW_BLOCKS + 1),
+            detection_times: DetectionTimes::default(),
+            bridge_contracts: vec![BridgeContract::Aurora],
+            token_validator: None,
+            usd_oracle: None,
+            #[cfg(feature = "rpc")]
+            metadata_cache: None,
+        }
+    }
+
+    /// Blocks below `height` are still processed to build up internal state, but
+    /// no events are sent to the handler for them
+    pub fn with_emit_after_block_height(mut self, height: BlockHeight) -> Self {
+        self.emit_after_block_height = Some(height);
+        self
+    }
+
+    /// Ref pool changes with IDs above `max_pool_id` are skipped, 1,000,000 by default
+    pub fn with_max_pool_id(mut self, max_pool_id: u64) -> Self {
+        self.max_pool_id = max_pool_id;
+        self
+    }
+
+    /// When enabled, pools changed by several receipts in one block are reported once
+    /// with their final state. Disabled by default.
+    pub fn with_coalesce_pool_changes_per_block(mut self, coalesce: bool) -> Self {
+        self.coalesce_pool_changes_per_block = coalesce;
+        self
+    }
+
+    /// Emits only a share of the pool changes of busy pools, e.g. `("AIDOLS-*", 0.1)`
+    /// emits 10% of Aidols pool changes. Which events are dropped depends only on the
+    /// receipt and pool ID, so reindexing drops the same ones. Exact pool IDs take
+    /// priority over prefixes, and longer prefixes over shorter ones.
+    pub fn with_sampling_rate(
+        mut self,
+        sampling_rate: impl IntoIterator<Item = (PoolId, f32)>,
+    ) -> Self {
+        self.sampling_rate = sampling_rate.into_iter().collect();
+        self
+    }
+
+    /// Adds a [`FeeBreakdown`] to Ref swaps. Fees are taken from the last pool change
+    /// seen by the indexer, so swaps in pools that haven't changed since it started
+    /// have no breakdown.
+    pub fn with_fee_breakdown(mut self) -> Self {
+        self.pool_fees = Some(HashMap::new());
+        self
+    }
+
+    /// Cumulative volumes only grow, a decrease means a rollback or a bug in
+    /// deserialization
+    fn check_volumes_increased(&mut self, block_height: BlockHeight) {
+        for (pool_id, volumes) in std::mem::take(&mut self.block_pool_volumes) {
+            if let Some(previous) = self.pool_volumes.get(&pool_id) {
+                for (i, (previous, current)) in previous.iter().zip(&volumes).enumerate() {
+                    if current.input < previous.input || current.output < previous.output {
+                        log::warn!(
+                            "Volume of token #{i} in pool {pool_id} decreased in block {block_height}: {previous:?} -> {current:?}"
+                        );
+                    }
+                }
+            }
+            self.pool_volumes.insert(pool_id, volumes);
+        }
+    }
+
+    /// For pools that were traded in and changed in this block, sums up how much of
+    /// each reserve was swapped in, e.g. 500 bps if 5% of the token reserve was
+    /// swapped in. Only simple Ref pools and Aidols pools have raw reserves.
+    async fn report_pool_utilization(&mut self, block_height: BlockHeight) {
+        let reserves = std::mem::take(&mut self.block_pool_reserves);
+        let emit = self.should_emit(block_height);
+        for (pool_id, amounts) in std::mem::take(&mut self.block_pool_swap_amounts) {
+            let Some(reserves) = reserves.get(&pool_id) else {
+                continue;
+            };
+            let utilization_bps = amounts
+                .iter()
+                .filter_map(|(token, amount)| {
+                    let reserve = *reserves.get(token)?;
+                    (reserve != 0).then(|| amount.saturating_mul(10_000) / reserve)
+                })
+                .fold(0u128, u128::saturating_add);
+            if emit {
+                self.handler
+                    .on_pool_utilization(
+                        pool_id,
+                        utilization_bps.try_into().unwrap_or(u32::MAX),
+                        block_height,
+                    )
+                    .await;
+            }
+        }
+    }
+
+    async fn report_pool_swap_stats(&mut self, block_height: BlockHeight) {
+        let swap_counts = std::mem::take(&mut self.block_pool_swap_counts);
+        if !self.should_emit(block_height) {
+            return;
+        }
+        for (pool_id, (successful, total)) in swap_counts {
+            self.handler
+                .on_pool_swap_stats(pool_id, successful as f32 / total as f32, block_height)
+                .await;
+        }
+    }
+
+    async fn report_aidols_pool_diffs(&mut self, block_height: BlockHeight) {
+        let changed_pools = std::mem::take(&mut self.block_aidols_pools);
+        if !self.should_emit(block_height) {
+            return;
+        }
+        for (pool_id, (previous, receipt_id, context)) in changed_pools {
+            let Some(context) = context else {
+                log::warn!("Receipt {receipt_id} that changed Aidols pool {pool_id} wasn't seen");
+                continue;
+            };
+            let diff = AidolsPoolDiff::between(&previous, &self.aidols_pool_states[&pool_id]);
+            // A pool can change and change back within a block
+            if diff.is_empty() {
+                continue;
+            }
+            self.handler
+                .on_aidols_pool_diff(pool_id, diff, context)
+                .await;
+        }
+    }
+
+    fn wrap_near_contract_id(&self) -> &'static str {
+        if self.is_testnet {
+            ref_trade_detection::TESTNET_WRAP_NEAR_CONTRACT_ID
+        } else {
+            ref_trade_detection::WRAP_NEAR_CONTRACT_ID
+        }
+    }
+
+    fn is_already_processed(&self, block_height: BlockHeight) -> bool {
+        self.last_processed_block
+            .is_some_and(|last_processed| block_height <= last_processed)
+    }
+
+    fn should_emit(&self, block_height: BlockHeight) -> bool {
+        self.emit_after_block_height
+            .is_none_or(|emit_after| block_height >= emit_after)
+    }
+
+    /// Replaces the default list of Ref aggregator contracts
+    pub fn with_aggregator_contracts(
+        mut self,
+        aggregator_contracts: impl IntoIterator<Item = AccountId>,
+    ) -> Self {
+        self.aggregator_contracts = aggregator_contracts.into_iter().collect();
+        self
+    }
+
+    /// Replaces the default list of bridges, only Aurora
+    pub fn with_bridge_contracts(
+        mut self,
+        bridge_contracts: impl IntoIterator<Item = BridgeContract>,
+    ) -> Self {
+        self.bridge_contracts = bridge_contracts.into_iter().collect();
+        self
+    }
+
+    /// Replaces the default list of relayers and their depths, `ref.hot.tg` at 2
+    pub fn with_relayer_depths(
+        mut self,
+        relayer_depths: impl IntoIterator<Item = (AccountId, u8)>,
+    ) -> Self {
+        self.relayer_depths = relayer_depths.into_iter().collect();
+        self
+    }
+
+    /// Skips changes of Ref simple pools that have a token rejected by `validator`,
+    /// since anyone can create a simple pool with a fake token contract. Stable and
+    /// rated pools are created by Ref and aren't checked.
+    pub fn with_token_validator(mut self, validator: impl TokenValidator) -> Self {
+        self.token_validator = Some(Box::new(validator));
+        self
+    }
+
+    /// Fills `amount_in_usd` and `amount_out_usd` of Ref swaps. Tokens are priced
+    /// in wNEAR using Ref simple pools paired with wNEAR, and wNEAR is priced using
+    /// `near_usd_pool`, a wNEAR / USD stablecoin pool whose stablecoin has
+    /// `usd_token_decimals` decimals.
+    pub fn with_usd_oracle(
+        mut self,
+        mode: OracleMode,
+        near_usd_pool: PoolId,
+        usd_token_decimals: u8,
+    ) -> Self {
+        self.usd_oracle = Some(UsdOracle::new(
+            mode,
+            near_usd_pool,
+            usd_token_decimals,
+            self.wrap_near_contract_id().parse().unwrap(),
+        ));
+        self
+    }
+
+    /// Fetches metadata of tokens the first time they're traded and reports them
+    /// with [`TradeEventHandler::on_new_token`]. The cache can be shared between
+    /// indexers.
+    #[cfg(feature = "rpc")]
+    pub fn with_metadata_cache(mut self, metadata_cache: Arc<RwLock<MetadataCache>>) -> Self {
+        self.metadata_cache = Some(metadata_cache);
+        self
+    }
+
+    /// Average processing time of the last [`LATENCY_WINDOW_BLOCKS`] blocks, 0 if
+    /// no blocks were processed yet
+    pub fn average_latency_ns(&self) -> u64 {
+        if self.block_latencies_ns.is_empty() {
+            return 0;
+        }
+        self.block_latencies_ns.iter().sum::<u64>() / self.block_latencies_ns.len() as u64
+    }
+
+    pub fn stats(&self) -> IndexerStats {
+        self.stats.clone()
+    }
+
+    pub fn handler(&self) -> &T {
+        &self.handler
+    }
+
+    pub fn handler_mut(&mut self) -> &mut T {
+        &mut self.handler
+    }
+
+    /// Replaces the handler, events of the following blocks go to the new one.
+    /// Anything the old handler buffered and didn't flush yet is dropped.
+    pub fn set_handler(&mut self, handler: T) {
+        self.handler = handler;
+    }
+}
+
+/// Bounded set of receipt IDs, evicts the oldest IDs first.
+struct ProcessedReceipts {
+    ids: HashSet<CryptoHash>,
+    order: VecDeque<CryptoHash>,
+    capacity: usize,
+}
+
+impl ProcessedReceipts {
+    fn new(capacity: usize) -> Self {
+        Self {
+            ids: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Returns `false` if the receipt was already processed
+    fn insert(&mut self, receipt_id: CryptoHash) -> bool {
+        if !self.ids.insert(receipt_id) {
+            return false;
+        }
+        self.order.push_back(receipt_id);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
 }
 
+/// Receives detected events. Callbacks with a default body are optional, but
+/// wrapping handlers ([`dragonbot_grouping::GroupDragonbotSwaps`] and
+/// [`social_correlation::SocialCorrelator`]) must forward every one of them, so a
+/// new callback has to be added to both wrappers too.
 #[async_trait]
 pub trait TradeEventHandler: Send + Sync + 'static {
     async fn on_raw_pool_swap(&mut self, context: TradeContext, swap: RawPoolSwap);
@@ -42,6 +479,13 @@ pub trait TradeEventHandler: Send + Sync + 'static {
         balance_changes: BalanceChangeSwap,
     );
     async fn on_pool_change(&mut self, pool: PoolChangeEvent);
+    /// Called once per block with all pool changes of the block, in order.
+    /// Calls `on_pool_change` for each event unless overridden.
+    async fn on_bulk_pool_update(&mut self, events: Vec<PoolChangeEvent>) {
+        for event in events {
+            self.on_pool_change(event).await;
+        }
+    }
     async fn on_memecooking_deposit(&mut self, context: TradeContext, deposit: DepositEvent);
     async fn on_memecooking_withdraw(&mut self, context: TradeContext, withdraw: WithdrawEvent);
     async fn on_liquidity_pool(
@@ -50,6 +494,230 @@ pub trait TradeEventHandler: Send + Sync + 'static {
         pool_id: PoolId,
         tokens: HashMap<AccountId, i128>,
     );
+    /// Called when a stable or rated Ref pool starts ramping its amplification
+    /// coefficient from `from_amp` to `to_amp`, reaching it at `ramp_end_time`.
+    /// The first state of a pool seen by the indexer is only remembered, not reported.
+    async fn on_amp_ramp_started(
+        &mut self,
+        _pool_id: PoolId,
+        _from_amp: u128,
+        _to_amp: u128,
+        _ramp_end_time: u64,
+    ) {
+    }
+    /// Called for each Aidols swap that charged a wNEAR commission. `total_commission`
+    /// is the sum of commissions collected by the pool since the indexer started.
+    async fn on_commission_collected(
+        &mut self,
+        _context: TradeContext,
+        _pool_id: PoolId,
+        _commission: Balance,
+        _total_commission: Balance,
+    ) {
+    }
+    /// Called after the pool swaps of a HOT zap whose output is sent to
+    /// `output_account` instead of the trader
+    async fn on_output_redirected(&mut self, _context: TradeContext, _output_account: AccountId) {}
+    /// Called when Ref LP shares are sent to another account with `mft_transfer_call`,
+    /// e.g. when depositing them to a farm
+    async fn on_lp_share_transfer(
+        &mut self,
+        _context: TradeContext,
+        _pool_id: PoolId,
+        _sender: AccountId,
+        _receiver: AccountId,
+        _amount: Balance,
+    ) {
+    }
+    /// Called when an account withdraws `amount` of its NEAR storage deposit from Ref
+    async fn on_storage_withdrawn(
+        &mut self,
+        _context: TradeContext,
+        _account_id: AccountId,
+        _amount: Balance,
+    ) {
+    }
+    /// Called when an account removes itself from Ref with `storage_unregister`
+    async fn on_account_unregistered(&mut self, _context: TradeContext, _account_id: AccountId) {}
+    /// Reserved for funding payments of perpetual markets, `amount` is positive if
+    /// the trader receives it. Nothing calls this yet, no supported DEX has perpetuals.
+    async fn on_funding_payment(
+        &mut self,
+        _context: TradeContext,
+        _token: AccountId,
+        _amount: i128,
+        _market_id: String,
+    ) {
+    }
+    /// Called when `trader` withdraws `seed_amount` LP shares of a Ref pool from a farm.
+    /// Unlike `on_liquidity_pool`, the pool's liquidity doesn't change.
+    async fn on_farm_seed_withdrawn(
+        &mut self,
+        _context: TradeContext,
+        _pool_id: PoolId,
+        _seed_amount: Balance,
+        _trader: AccountId,
+    ) {
+    }
+    /// Called before `on_balance_change_swap` for swaps where
+    /// [`BalanceChangeSwap::is_flash_swap`] is true
+    async fn on_flash_swap(&mut self, _context: TradeContext, _balance_changes: BalanceChangeSwap) {
+    }
+    /// Called at the end of a block for each pool that was traded in, with the
+    /// amounts swapped in relative to the pool's reserves
+    async fn on_pool_utilization(
+        &mut self,
+        _pool_id: PoolId,
+        _utilization_bps: u32,
+        _block_height: BlockHeight,
+    ) {
+    }
+    /// Called at the end of a block for each Ref pool that had swaps, with the share
+    /// of swap receipts that succeeded
+    async fn on_pool_swap_stats(
+        &mut self,
+        _pool_id: PoolId,
+        _success_rate: f32,
+        _block_height: BlockHeight,
+    ) {
+    }
+    /// Called when a Ref swap fails because the pool is paused, once until the pool
+    /// is unfrozen
+    async fn on_pool_frozen(&mut self, _pool_id: PoolId, _context: TradeContext) {}
+    /// Called on the first successful swap in a pool after `on_pool_frozen`
+    async fn on_pool_unfrozen(&mut self, _pool_id: PoolId, _context: TradeContext) {}
+    /// Called when a stable or rated pool charges a fee on `remove_liquidity_by_tokens`,
+    /// for each token. The fee is taken in shares and reported as the amount of each
+    /// token these shares are worth right after the withdrawal.
+    async fn on_withdrawal_fee_charged(
+        &mut self,
+        _pool_id: PoolId,
+        _fee_token: AccountId,
+        _fee_amount: Balance,
+        _context: TradeContext,
+    ) {
+    }
+    /// Called when tokens are deposited to a trader's Ref balance without a swap,
+    /// with an empty `ft_transfer_call` message
+    async fn on_ref_deposit(
+        &mut self,
+        _trader: AccountId,
+        _token: AccountId,
+        _amount: Balance,
+        _context: TradeContext,
+    ) {
+    }
+    /// Called when tokens are withdrawn from a trader's Ref balance
+    async fn on_ref_withdraw(
+        &mut self,
+        _trader: AccountId,
+        _token: AccountId,
+        _amount: Balance,
+        _context: TradeContext,
+    ) {
+    }
+    /// Called when Ref returns part of an `ft_transfer_call` deposit as unused, the
+    /// token contract refunds it to `trader`
+    async fn on_deposit_refunded(
+        &mut self,
+        _trader: AccountId,
+        _token: AccountId,
+        _refunded_amount: Balance,
+        _context: TradeContext,
+    ) {
+    }
+    /// Called when an Aidols token is sent to a bridge contract
+    async fn on_cross_chain_swap(
+        &mut self,
+        _trader: AccountId,
+        _token: AccountId,
+        _amount: Balance,
+        _destination_chain: String,
+        _destination_address: String,
+        _context: TradeContext,
+    ) {
+    }
+    /// Called when a guardian of the Ref contract is added (`old_guardian` is `None`)
+    /// or removed (`new_guardian` is `None`). Guardians can pause the contract.
+    async fn on_guardian_changed(
+        &mut self,
+        _old_guardian: Option<AccountId>,
+        _new_guardian: Option<AccountId>,
+        _context: TradeContext,
+    ) {
+    }
+    /// Called after the raw pool swaps of each Ref swap receipt
+    async fn on_swap_gas_stats(&mut self, _context: TradeContext, _stats: SwapGasStats) {}
+    /// Called at the end of a block for each Aidols pool that changed in it, in
+    /// addition to `on_pool_change`. The context is of the last receipt that changed
+    /// the pool. Pools aren't reported the first time the indexer sees them.
+    async fn on_aidols_pool_diff(
+        &mut self,
+        _pool_id: PoolId,
+        _diff: AidolsPoolDiff,
+        _context: TradeContext,
+    ) {
+    }
+    /// Called when the owner of the Ref contract is changed
+    async fn on_owner_changed(
+        &mut self,
+        _old_owner: AccountId,
+        _new_owner: AccountId,
+        _context: TradeContext,
+    ) {
+    }
+    /// Called for each Ref pool change passed to `on_bulk_pool_update`, after it
+    async fn on_tvl_estimate(&mut self, _estimate: PoolTvlEstimate) {}
+    /// Called at the end of a block for each traded token. `buy_pressure` is the sum
+    /// of `amount_in` of swaps into the token, `sell_pressure` is the sum of
+    /// `amount_in` of swaps out of it.
+    async fn on_token_pressure(
+        &mut self,
+        _token: AccountId,
+        _buy_pressure: Balance,
+        _sell_pressure: Balance,
+        _block_height: BlockHeight,
+    ) {
+    }
+    /// Called when an Aidols token is burned
+    async fn on_token_burn(
+        &mut self,
+        _context: TradeContext,
+        _token: AccountId,
+        _amount: Balance,
+        _burner: AccountId,
+    ) {
+    }
+    /// Called the first time a token is traded, if the indexer has a metadata cache
+    #[cfg(feature = "rpc")]
+    async fn on_new_token(&mut self, _token: AccountId, _metadata: FtMetadata) {}
+    /// Called when a user moves funds from their wallet into a protocol's vault
+    async fn on_deposit_to_protocol(
+        &mut self,
+        _context: TradeContext,
+        _transfer: ProtocolTransferEvent,
+    ) {
+    }
+    /// Called when a user requests to move funds from a protocol's vault back to
+    /// their wallet
+    async fn on_withdrawal_from_protocol(
+        &mut self,
+        _context: TradeContext,
+        _transfer: ProtocolTransferEvent,
+    ) {
+    }
+    /// Called when an account publishes a post on NEAR Social, with the text of
+    /// the post
+    async fn on_social_post(&mut self, _context: TradeContext, _content: String) {}
+    /// Called by [`social_correlation::SocialCorrelator`] when a trader posts on
+    /// NEAR Social within a few blocks of a swap
+    async fn on_social_trade_post(
+        &mut self,
+        _trader: AccountId,
+        _post_content: String,
+        _swap: BalanceChangeSwap,
+    ) {
+    }
     async fn flush_events(&mut self, block_height: BlockHeight);
 }
 
@@ -58,12 +726,19 @@ impl<T: TradeEventHandler> Indexer for TradeIndexer<T> {
     type Error = String;
 
     async fn process_block(&mut self, block: &StreamerMessage) -> Result<(), Self::Error> {
-        let ref_contract_id = if self.is_testnet {
-            TESTNET_REF_CONTRACT_ID
-        } else {
-            REF_CONTRACT_ID
-        };
-        let aidols_contract_id = AIDOLS_CONTRACT_ID;
+        if self.is_already_processed(block.block.header.height) {
+            log::debug!(
+                "Skipping already processed block {}",
+                block.block.header.height
+            );
+            return Ok(());
+        }
+        self.block_started_at = Some(Instant::now());
+        if let Some(usd_oracle) = &mut self.usd_oracle {
+            usd_oracle.start_block();
+        }
+        let emit = self.should_emit(block.block.header.height);
+        let mut pool_changes = Vec::new();
         for shard in block.shards.iter() {
             for state_change in shard.state_changes.iter() {
                 if let StateChangeValueView::DataUpdate {
@@ -72,106 +747,267 @@ impl<T: TradeEventHandler> Indexer for TradeIndexer<T> {
                     value,
                 } = &state_change.value
                 {
-                    if account_id == ref_contract_id {
-                        let receipt_id =
-                            if let StateChangeCauseView::ReceiptProcessing { receipt_hash } =
-                                &state_change.cause
-                            {
-                                receipt_hash
+                    match self.state_contracts.get(account_id).copied() {
+                        Some(StateContract::Ref) => {
+                            let receipt_id =
+                                if let StateChangeCauseView::ReceiptProcessing { receipt_hash } =
+                                    &state_change.cause
+                                {
+                                    receipt_hash
+                                } else {
+                                    log::warn!(
+                                        "Update not caused by a receipt in block {}",
+                                        block.block.header.height
+                                    );
+                                    continue;
+                                };
+                            let key = key.as_slice();
+                            // Prefix changed from b"p" to 0x00 in https://github.com/ref-finance/ref-contracts/commit/a196f4a18368f0c3d62e80ba2788c350c94e85b2
+                            #[allow(clippy::if_same_then_else)]
+                            let without_prefix = if key.starts_with(&[0]) {
+                                &key[1..]
+                            } else if key.starts_with(b"p") {
+                                &key[1..]
                             } else {
-                                log::warn!(
-                                    "Update not caused by a receipt in block {}",
-                                    block.block.header.height
-                                );
                                 continue;
                             };
-                        let key = key.as_slice();
-                        // Prefix changed from b"p" to 0x00 in https://github.com/ref-finance/ref-contracts/commit/a196f4a18368f0c3d62e80ba2788c350c94e85b2
-                        #[allow(clippy::if_same_then_else)]
-                        let without_prefix = if key.starts_with(&[0]) {
-                            &key[1..]
-                        } else if key.starts_with(b"p") {
-                            &key[1..]
-                        } else {
-                            continue;
-                        };
-                        if without_prefix.len() != 8 {
-                            log::warn!("Invalid pool key: {:02x?}", key);
-                            continue;
-                        }
-                        let pool_id = u64::from_le_bytes(without_prefix.try_into().unwrap());
-                        log::debug!("Pool changed: {pool_id}");
-                        if let Ok(pool) = <ref_finance_state::Pool as BorshDeserialize>::deserialize(
-                            &mut value.as_slice(),
-                        ) {
-                            if pool_id > 420_000 {
-                                log::warn!("Pool ID too high, probably a bug: {pool_id}. If Ref actually has that many pools, increase the number in {}:{} to a reasonable amount", file!(), line!() - 1);
+                            if without_prefix.len() != 8 {
+                                log::warn!("Invalid pool key: {:02x?}", key);
                                 continue;
                             }
-
-                            let pool = PoolChangeEvent {
-                                pool_id: ref_trade_detection::create_ref_pool_id(pool_id),
-                                receipt_id: *receipt_id,
-                                block_timestamp_nanosec: block.block.header.timestamp_nanosec
-                                    as u128,
-                                block_height: block.block.header.height,
-                                pool: PoolType::Ref(pool),
-                            };
-                            self.handler.on_pool_change(pool).await;
-                        }
-                    } else if account_id == aidols_contract_id {
-                        let receipt_id =
-                            if let StateChangeCauseView::ReceiptProcessing { receipt_hash } =
-                                &state_change.cause
+                            let pool_id = u64::from_le_bytes(without_prefix.try_into().unwrap());
+                            log::debug!("Pool changed: {pool_id}");
+                            if let Ok(mut pool) =
+                                <ref_finance_state::Pool as BorshDeserialize>::deserialize(
+                                    &mut value.as_slice(),
+                                )
                             {
-                                receipt_hash
+                                if pool_id > self.max_pool_id {
+                                    log::warn!("Pool ID too high, probably a bug: {pool_id} is above max_pool_id {}. If Ref actually has that many pools, increase it with TradeIndexer::with_max_pool_id", self.max_pool_id);
+                                    continue;
+                                }
+                                if let ref_finance_state::Pool::Unknown {
+                                    discriminant,
+                                    raw_bytes,
+                                } = &pool
+                                {
+                                    log::warn!(
+                                    "Unknown Ref pool type {discriminant} for pool {pool_id}, add support for it. First bytes: {:02x?}",
+                                    &raw_bytes[..raw_bytes.len().min(32)]
+                                );
+                                }
+                                if let Err(err) = pool.validate() {
+                                    log::warn!("Pool {pool_id} failed validation, skipping: {err}");
+                                    continue;
+                                }
+                                if let Err(account_id) = pool.canonicalize_token_account_ids() {
+                                    log::warn!("Pool {pool_id} has an invalid token account ID {account_id:?}, skipping");
+                                    continue;
+                                }
+                                if let Some(token_validator) = &self.token_validator {
+                                    if let Some(token) =
+                                        invalid_pool_token(token_validator.as_ref(), &pool).await
+                                    {
+                                        log::debug!(
+                                            "Pool {pool_id} has unrecognized token {token}, skipping"
+                                        );
+                                        continue;
+                                    }
+                                }
+                                for issue in pool.integrity_issues() {
+                                    log::warn!("Pool {pool_id} failed integrity check: {issue}");
+                                }
+
+                                let ref_pool_id = ref_trade_detection::create_ref_pool_id(pool_id);
+                                if let (Some(pool_fees), Some(fees)) =
+                                    (&mut self.pool_fees, pool.fees())
+                                {
+                                    pool_fees.insert(ref_pool_id.clone(), fees);
+                                }
+                                let amp_ramp = pool.amp_ramp();
+                                if let Some(reserves) = pool.reserves() {
+                                    let reserves: HashMap<AccountId, Balance> = reserves
+                                        .into_iter()
+                                        .filter_map(|(token, amount)| {
+                                            Some((token.parse().ok()?, amount))
+                                        })
+                                        .collect();
+                                    if let Some(usd_oracle) = &mut self.usd_oracle {
+                                        usd_oracle.update(&ref_pool_id, &reserves);
+                                    }
+                                    self.block_pool_reserves
+                                        .insert(ref_pool_id.clone(), reserves);
+                                }
+                                if matches!(
+                                    pool,
+                                    ref_finance_state::Pool::StableSwapPool(_)
+                                        | ref_finance_state::Pool::RatedSwapPool(_)
+                                ) {
+                                    self.block_stable_pools
+                                        .insert(*receipt_id, (ref_pool_id.clone(), pool.clone()));
+                                }
+                                if let Some(volumes) = pool.volumes() {
+                                    self.block_pool_volumes
+                                        .push((ref_pool_id.clone(), volumes.to_vec()));
+                                }
+                                let pool = PoolChangeEvent {
+                                    pool_id: ref_pool_id.clone(),
+                                    receipt_id: *receipt_id,
+                                    block_timestamp_nanosec: block.block.header.timestamp_nanosec
+                                        as u128,
+                                    block_height: block.block.header.height,
+                                    pool: PoolType::Ref(pool),
+                                    spot_price: None,
+                                    sampled: false,
+                                };
+                                pool_changes.push(pool);
+                                *self.stats.pool_changes_by_dex.entry("ref").or_insert(0) += 1;
+                                if let Some(amp_ramp) = amp_ramp {
+                                    let previous =
+                                        self.amp_ramps.insert(ref_pool_id.clone(), amp_ramp);
+                                    if previous.is_some_and(|previous| {
+                                        previous.target_amp_factor != amp_ramp.target_amp_factor
+                                            || previous.init_amp_time != amp_ramp.init_amp_time
+                                    }) && emit
+                                    {
+                                        self.handler
+                                            .on_amp_ramp_started(
+                                                ref_pool_id,
+                                                amp_ramp.init_amp_factor,
+                                                amp_ramp.target_amp_factor,
+                                                amp_ramp.stop_amp_time,
+                                            )
+                                            .await;
+                                    }
+                                }
                             } else {
                                 log::warn!(
-                                    "Update not caused by a receipt in block {}",
-                                    block.block.header.height
-                                );
+                                "Failed to deserialize Ref pool {pool_id} with type {:?}. First bytes: {:02x?}",
+                                value.as_slice().first(),
+                                &value.as_slice()[..value.as_slice().len().min(32)]
+                            );
+                            }
+                        }
+                        Some(StateContract::Aidols) => {
+                            let receipt_id =
+                                if let StateChangeCauseView::ReceiptProcessing { receipt_hash } =
+                                    &state_change.cause
+                                {
+                                    receipt_hash
+                                } else {
+                                    log::warn!(
+                                        "Update not caused by a receipt in block {}",
+                                        block.block.header.height
+                                    );
+                                    continue;
+                                };
+                            let key = key.as_slice();
+                            let Some(token_id) = aidols_state::pool_key_token_id(
+                                key,
+                                aidols_state::POOL_KEY_PREFIXES,
+                            ) else {
+                                if aidols_state::POOL_KEY_PREFIXES
+                                    .iter()
+                                    .any(|prefix| key.starts_with(prefix))
+                                {
+                                    log::warn!("Invalid account id: {:02x?}", key);
+                                }
                                 continue;
                             };
-                        let key = key.as_slice();
-                        #[allow(clippy::if_same_then_else)]
-                        let mut without_prefix = if let Some(data) = key.strip_prefix(&[0x00]) {
-                            data
-                        } else {
-                            continue;
-                        };
-                        let Ok(token_id) =
-                            <AccountId as BorshDeserialize>::deserialize(&mut without_prefix)
-                        else {
-                            log::warn!("Invalid account id: {:02x?}", key);
-                            continue;
-                        };
-                        println!("token_id: {:?}", token_id);
-                        log::debug!("Pool changed: {token_id}");
-                        if let Ok(pool) =
-                            <aidols_state::AidolsPoolState as BorshDeserialize>::deserialize(
-                                &mut value.as_slice(),
-                            )
-                        {
-                            let pool = PoolChangeEvent {
-                                pool_id: aidols_trade_detection::create_aidols_pool_id(&token_id),
-                                receipt_id: *receipt_id,
-                                block_timestamp_nanosec: block.block.header.timestamp_nanosec
-                                    as u128,
-                                block_height: block.block.header.height,
-                                pool: PoolType::Aidols(AidolsPool {
-                                    token_id: token_id.clone(),
-                                    token_hold: pool.token_hold,
-                                    wnear_hold: pool.wnear_hold,
-                                    is_deployed: pool.is_deployed,
-                                    is_tradable: pool.is_tradable,
-                                }),
-                            };
-                            self.handler.on_pool_change(pool).await;
+                            log::debug!("Pool changed: {token_id}");
+                            if let Some((pool, version)) =
+                                aidols_state::AidolsPoolState::deserialize_versioned(
+                                    value.as_slice(),
+                                    self.aidols_state_version,
+                                )
+                            {
+                                if version != self.aidols_state_version {
+                                    log::info!(
+                                    "Aidols pool state layout changed from version {} to {version}",
+                                    self.aidols_state_version
+                                );
+                                    self.aidols_state_version = version;
+                                }
+                                let aidols_pool_id =
+                                    aidols_trade_detection::create_aidols_pool_id(&token_id);
+                                self.block_pool_reserves.insert(
+                                    aidols_pool_id.clone(),
+                                    HashMap::from_iter([
+                                        (token_id.clone(), pool.token_hold),
+                                        ("wrap.near".parse().unwrap(), pool.wnear_hold),
+                                    ]),
+                                );
+                                // Pools seen for the first time have nothing to diff against
+                                if let Some(previous) = self
+                                    .aidols_pool_states
+                                    .insert(aidols_pool_id.clone(), pool.clone())
+                                {
+                                    self.block_aidols_pools
+                                        .entry(aidols_pool_id.clone())
+                                        .and_modify(|(_, last_receipt_id, _)| {
+                                            *last_receipt_id = *receipt_id
+                                        })
+                                        .or_insert((previous, *receipt_id, None));
+                                }
+                                let spot_price = pool.spot_price_in_wnear();
+                                let pool = PoolChangeEvent {
+                                    pool_id: aidols_pool_id,
+                                    receipt_id: *receipt_id,
+                                    block_timestamp_nanosec: block.block.header.timestamp_nanosec
+                                        as u128,
+                                    block_height: block.block.header.height,
+                                    pool: PoolType::Aidols(AidolsPool {
+                                        token_id: token_id.clone(),
+                                        token_hold: pool.token_hold,
+                                        wnear_hold: pool.wnear_hold,
+                                        is_deployed: pool.is_deployed,
+                                        is_tradable: pool.is_tradable,
+                                    }),
+                                    spot_price,
+                                    sampled: false,
+                                };
+                                pool_changes.push(pool);
+                                *self.stats.pool_changes_by_dex.entry("aidols").or_insert(0) += 1;
+                            } else {
+                                log::warn!("Aidols pool state for key {key:02x?} doesn't match any known layout, add a new version to aidols_state::KNOWN_VERSIONS: {:02x?}", value.as_slice());
+                            }
                         }
+                        None => {}
                     }
                 }
             }
         }
+        if self.coalesce_pool_changes_per_block {
+            pool_changes = coalesce_pool_changes(pool_changes);
+        }
+        if !self.sampling_rate.is_empty() {
+            pool_changes = sample_pool_changes(pool_changes, &self.sampling_rate);
+        }
+        // From the pool changes that are actually emitted, priced after all oracle
+        // updates of the block
+        let tvl_estimates = pool_changes
+            .iter()
+            .filter_map(|event| {
+                let tvl_near =
+                    estimate_tvl_near(event.as_ref_pool()?, self.wrap_near_contract_id());
+                let tvl_usd = tvl_near
+                    .and_then(|tvl_near| self.usd_oracle.as_ref()?.near_usd_value(tvl_near));
+                Some(PoolTvlEstimate {
+                    pool_id: event.pool_id.clone(),
+                    tvl_near,
+                    tvl_usd,
+                    timestamp: event.block_timestamp_nanosec,
+                })
+            })
+            .collect::<Vec<_>>();
+        if !pool_changes.is_empty() && emit {
+            self.handler.on_bulk_pool_update(pool_changes).await;
+        }
+        if emit {
+            for estimate in tvl_estimates {
+                self.handler.on_tvl_estimate(estimate).await;
+            }
+        }
         Ok(())
     }
 
@@ -181,39 +1017,401 @@ impl<T: TradeEventHandler> Indexer for TradeIndexer<T> {
         transaction: &IncompleteTransaction,
         block: &StreamerMessage,
     ) -> Result<(), Self::Error> {
-        ref_trade_detection::detect(
-            receipt,
-            transaction,
-            block,
-            &mut self.handler,
-            self.is_testnet,
-        )
-        .await;
-        meme_cooking_deposit_detection::detect(
-            receipt,
-            transaction,
-            block,
-            &mut self.handler,
-            self.is_testnet,
-        )
-        .await;
-        aidols_trade_detection::detect(
-            receipt,
-            transaction,
-            block,
-            &mut self.handler,
-            self.is_testnet,
-        )
-        .await;
+        if self.is_already_processed(block.block.header.height) {
+            return Ok(());
+        }
+        let receipt_id = receipt.receipt.receipt.receipt_id;
+        if !self.processed_receipts.insert(receipt_id) {
+            log::debug!("Skipping already processed receipt {receipt_id}");
+            return Ok(());
+        }
+        self.stats.total_receipts_processed += 1;
+        let emit = self.should_emit(block.block.header.height);
+        let swaps = if emit {
+            detect_receipt(
+                receipt,
+                transaction,
+                block,
+                &mut self.handler,
+                self.is_testnet,
+                &self.aggregator_contracts,
+                &self.relayer_depths,
+                self.pool_fees.as_ref(),
+                self.usd_oracle.as_ref(),
+                &self.bridge_contracts,
+                &mut self.aidols_commissions,
+                &mut self.detection_times,
+            )
+            .await
+        } else {
+            detect_receipt(
+                receipt,
+                transaction,
+                block,
+                &mut NoopHandler::default(),
+                self.is_testnet,
+                &self.aggregator_contracts,
+                &self.relayer_depths,
+                self.pool_fees.as_ref(),
+                self.usd_oracle.as_ref(),
+                &self.bridge_contracts,
+                &mut self.aidols_commissions,
+                &mut self.detection_times,
+            )
+            .await
+        };
+        // Tokens traded during warm-up are fetched and reported the first time
+        // they're traded after it
+        #[cfg(feature = "rpc")]
+        if let Some(metadata_cache) = self.metadata_cache.as_ref().filter(|_| emit) {
+            for (_, swap) in swaps.iter() {
+                for token in [&swap.token_in, &swap.token_out] {
+                    if let Some(metadata) = MetadataCache::fetch_if_new(metadata_cache, token).await
+                    {
+                        self.handler.on_new_token(token.clone(), metadata).await;
+                    }
+                }
+            }
+        }
+        for pool_id in ref_trade_detection::failed_swap_pools(receipt, self.is_testnet) {
+            self.block_pool_swap_counts.entry(pool_id).or_default().1 += 1;
+        }
+        for (pool_id, fee_shares) in
+            ref_trade_detection::liquidity_removal_fees(receipt, self.is_testnet)
+        {
+            let Some((_, pool_after)) = self
+                .block_stable_pools
+                .get(&receipt_id)
+                .filter(|(stable_pool_id, _)| *stable_pool_id == pool_id)
+            else {
+                continue;
+            };
+            if !emit {
+                continue;
+            }
+            for (fee_token, fee_amount) in pool_after.share_value(fee_shares) {
+                self.handler
+                    .on_withdrawal_fee_charged(
+                        pool_id.clone(),
+                        fee_token,
+                        fee_amount,
+                        event_context(receipt, transaction, block),
+                    )
+                    .await;
+            }
+        }
+        for (_, last_receipt_id, context) in self.block_aidols_pools.values_mut() {
+            if *last_receipt_id == receipt_id {
+                *context = Some(event_context(receipt, transaction, block));
+            }
+        }
+        for pool_id in ref_trade_detection::paused_swap_pools(receipt, self.is_testnet) {
+            if self.frozen_pools.insert(pool_id.clone()) && emit {
+                self.handler
+                    .on_pool_frozen(pool_id, event_context(receipt, transaction, block))
+                    .await;
+            }
+        }
+        for (dex, swap) in swaps {
+            *self.stats.swaps_by_dex.entry(dex).or_insert(0) += 1;
+            if dex == "ref" {
+                let counts = self
+                    .block_pool_swap_counts
+                    .entry(swap.pool.clone())
+                    .or_default();
+                counts.0 += 1;
+                counts.1 += 1;
+                // Swaps in paused pools fail, so no balance changes are ever reported
+                // for them, a successful one means the pool was unpaused
+                if self.frozen_pools.remove(&swap.pool) && emit {
+                    self.handler
+                        .on_pool_unfrozen(
+                            swap.pool.clone(),
+                            event_context(receipt, transaction, block),
+                        )
+                        .await;
+                }
+            }
+            self.block_token_pressure
+                .entry(swap.token_out.clone())
+                .or_default()
+                .0 += swap.amount_in;
+            self.block_token_pressure
+                .entry(swap.token_in.clone())
+                .or_default()
+                .1 += swap.amount_in;
+            *self
+                .block_pool_swap_amounts
+                .entry(swap.pool)
+                .or_default()
+                .entry(swap.token_in)
+                .or_insert(0) += swap.amount_in;
+        }
         Ok(())
     }
 
     async fn process_block_end(&mut self, block: &StreamerMessage) -> Result<(), Self::Error> {
-        self.handler.flush_events(block.block.header.height).await;
+        if self.is_already_processed(block.block.header.height) {
+            return Ok(());
+        }
+        self.stats.total_blocks_processed += 1;
+        self.block_stable_pools.clear();
+        self.detection_times.finish_block(block.block.header.height);
+        self.check_volumes_increased(block.block.header.height);
+        self.report_pool_utilization(block.block.header.height)
+            .await;
+        self.report_pool_swap_stats(block.block.header.height).await;
+        self.report_aidols_pool_diffs(block.block.header.height)
+            .await;
+        let token_pressure = std::mem::take(&mut self.block_token_pressure);
+        if self.should_emit(block.block.header.height) {
+            for (token, (buy_pressure, sell_pressure)) in token_pressure {
+                self.handler
+                    .on_token_pressure(
+                        token,
+                        buy_pressure,
+                        sell_pressure,
+                        block.block.header.height,
+                    )
+                    .await;
+            }
+        }
+        if self.should_emit(block.block.header.height) {
+            self.handler.flush_events(block.block.header.height).await;
+        }
+        self.last_processed_block = Some(block.block.header.height);
+        if let Some(started_at) = self.block_started_at.take() {
+            let latency_ns = started_at.elapsed().as_nanos() as u64;
+            log::debug!(
+                "Processed block {} in {latency_ns}ns",
+                block.block.header.height
+            );
+            self.block_latencies_ns.push_back(latency_ns);
+            if self.block_latencies_ns.len() > LATENCY_WINDOW_BLOCKS {
+                self.block_latencies_ns.pop_front();
+            }
+        }
         Ok(())
     }
 }
 
+/// Drops pool changes with probability `1 - rate` of their pool's sampling rate,
+/// deterministically based on the receipt and pool ID. Kept changes of sampled
+/// pools are marked as `sampled`.
+pub(crate) fn sample_pool_changes(
+    pool_changes: Vec<PoolChangeEvent>,
+    sampling_rate: &HashMap<PoolId, f32>,
+) -> Vec<PoolChangeEvent> {
+    pool_changes
+        .into_iter()
+        .filter_map(|mut event| {
+            let Some(rate) = pool_sampling_rate(&event.pool_id, sampling_rate) else {
+                return Some(event);
+            };
+            if rate >= 1.0 {
+                return Some(event);
+            }
+            let key = sampling_key(&event.receipt_id, &event.pool_id);
+            if (key as f64 / u64::MAX as f64) >= rate as f64 {
+                return None;
+            }
+            event.sampled = true;
+            Some(event)
+        })
+        .collect()
+}
+
+fn pool_sampling_rate(pool_id: &str, sampling_rate: &HashMap<PoolId, f32>) -> Option<f32> {
+    if let Some(rate) = sampling_rate.get(pool_id) {
+        return Some(*rate);
+    }
+    sampling_rate
+        .iter()
+        .filter_map(|(pattern, rate)| {
+            let prefix = pattern.strip_suffix('*')?;
+            pool_id.starts_with(prefix).then_some((prefix.len(), *rate))
+        })
+        .max_by_key(|(prefix_len, _)| *prefix_len)
+        .map(|(_, rate)| rate)
+}
+
+/// FNV-1a of the receipt and pool ID. `DefaultHasher` isn't guaranteed to give the
+/// same results across Rust versions, which would make sampling irreproducible.
+fn sampling_key(receipt_id: &CryptoHash, pool_id: &str) -> u64 {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+    receipt_id
+        .0
+        .iter()
+        .chain(pool_id.as_bytes())
+        .fold(FNV_OFFSET_BASIS, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+        })
+}
+
+/// Context of events that are detected in `on_receipt` instead of a detection
+/// module, the trader is the signer of the transaction
+fn event_context(
+    receipt: &TransactionReceipt,
+    transaction: &IncompleteTransaction,
+    block: &StreamerMessage,
+) -> TradeContext {
+    TradeContext {
+        trader: transaction.transaction.transaction.signer_id.clone(),
+        block_height: block.block.header.height,
+        block_timestamp_nanosec: block.block.header.timestamp_nanosec as u128,
+        transaction_id: transaction.transaction.transaction.hash,
+        receipt_id: receipt.receipt.receipt.receipt_id,
+    }
+}
+
+/// Keeps only the last change of each pool, in the order of those last changes
+pub(crate) fn coalesce_pool_changes(pool_changes: Vec<PoolChangeEvent>) -> Vec<PoolChangeEvent> {
+    let mut seen = HashSet::new();
+    let mut coalesced = pool_changes
+        .into_iter()
+        .rev()
+        .filter(|event| seen.insert(event.pool_id.clone()))
+        .collect::<Vec<_>>();
+    coalesced.reverse();
+    coalesced
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn detect_receipt(
+    receipt: &TransactionReceipt,
+    transaction: &IncompleteTransaction,
+    block: &StreamerMessage,
+    handler: &mut impl TradeEventHandler,
+    is_testnet: bool,
+    aggregator_contracts: &HashSet<AccountId>,
+    relayer_depths: &HashMap<AccountId, u8>,
+    pool_fees: Option<&HashMap<PoolId, PoolFees>>,
+    usd_oracle: Option<&UsdOracle>,
+    bridge_contracts: &[BridgeContract],
+    aidols_commissions: &mut HashMap<PoolId, Balance>,
+    detection_times: &mut DetectionTimes,
+) -> Vec<(&'static str, RawPoolSwap)> {
+    let mut swaps = Vec::new();
+    let ref_swaps = detection_times
+        .measure(
+            "ref",
+            ref_trade_detection::detect(
+                receipt,
+                transaction,
+                block,
+                handler,
+                is_testnet,
+                aggregator_contracts,
+                relayer_depths,
+                pool_fees,
+                usd_oracle,
+            ),
+        )
+        .await;
+    swaps.extend(ref_swaps.into_iter().map(|swap| ("ref", swap)));
+    detection_times
+        .measure(
+            "ref_farm",
+            ref_trade_detection::detect_farm_seed_withdrawals(
+                receipt,
+                transaction,
+                block,
+                handler,
+                is_testnet,
+            ),
+        )
+        .await;
+    detection_times
+        .measure(
+            "ref_deposits",
+            ref_trade_detection::detect_deposits_and_withdrawals(
+                receipt,
+                transaction,
+                block,
+                handler,
+                is_testnet,
+            ),
+        )
+        .await;
+    detection_times
+        .measure(
+            "ref_refunds",
+            ref_trade_detection::detect_deposit_refunds(
+                receipt,
+                transaction,
+                block,
+                handler,
+                is_testnet,
+            ),
+        )
+        .await;
+    detection_times
+        .measure(
+            "ref_admin",
+            ref_trade_detection::detect_admin_changes(
+                receipt,
+                transaction,
+                block,
+                handler,
+                is_testnet,
+            ),
+        )
+        .await;
+    detection_times
+        .measure(
+            "meme_cooking",
+            meme_cooking_deposit_detection::detect(
+                receipt,
+                transaction,
+                block,
+                handler,
+                is_testnet,
+            ),
+        )
+        .await;
+    let aidols_swaps = detection_times
+        .measure(
+            "aidols",
+            aidols_trade_detection::detect(
+                receipt,
+                transaction,
+                block,
+                handler,
+                is_testnet,
+                aidols_commissions,
+            ),
+        )
+        .await;
+    swaps.extend(aidols_swaps.into_iter().map(|swap| ("aidols", swap)));
+    detection_times
+        .measure(
+            "aidols_bridge",
+            aidols_trade_detection::detect_bridge_transfers(
+                receipt,
+                transaction,
+                block,
+                handler,
+                is_testnet,
+                bridge_contracts,
+            ),
+        )
+        .await;
+    detection_times
+        .measure(
+            "orderly",
+            orderly_transfer_detection::detect(receipt, transaction, block, handler, is_testnet),
+        )
+        .await;
+    detection_times
+        .measure(
+            "social",
+            social_post_detection::detect(receipt, transaction, block, handler, is_testnet),
+        )
+        .await;
+    swaps
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct TradeContext {
     trader: AccountId,
@@ -230,14 +1428,244 @@ pub struct RawPoolSwap {
     token_out: AccountId,
     amount_in: Balance,
     amount_out: Balance,
+    referral: Option<AccountId>,
+    /// Part of the fee paid to `referral`, None if the DEX doesn't report it
+    referral_commission: Option<Balance>,
+    fee_breakdown: Option<FeeBreakdown>,
+    /// Set when enabled with [`TradeIndexer::with_usd_oracle`] and the tokens have
+    /// known prices
+    amount_in_usd: Option<f64>,
+    amount_out_usd: Option<f64>,
 }
 
-#[derive(Debug, PartialEq)]
+impl RawPoolSwap {
+    /// Set for Ref swaps when enabled with [`TradeIndexer::with_fee_breakdown`]
+    pub fn fee_breakdown(&self) -> Option<&FeeBreakdown> {
+        self.fee_breakdown.as_ref()
+    }
+
+    /// Set when enabled with [`TradeIndexer::with_usd_oracle`] and `token_in` has a
+    /// known price
+    pub fn amount_in_usd(&self) -> Option<f64> {
+        self.amount_in_usd
+    }
+
+    /// Set when enabled with [`TradeIndexer::with_usd_oracle`] and `token_out` has a
+    /// known price
+    pub fn amount_out_usd(&self) -> Option<f64> {
+        self.amount_out_usd
+    }
+}
+
+/// Fees charged on a swap, in the input token
+#[derive(Debug, PartialEq, Clone)]
+pub struct FeeBreakdown {
+    pub total_fee: Balance,
+    pub exchange_fee: Balance,
+    pub referral_fee: Balance,
+}
+
+impl FeeBreakdown {
+    /// `exchange_fee` and `referral_fee` of Ref pools are obsolete and usually 0,
+    /// the current split is configured on the contract and isn't in pool states.
+    pub fn from_pool_fees(amount_in: Balance, fees: &PoolFees) -> Self {
+        // Only None if a fee is over 100%, which pools don't allow
+        let fee = |fee: u32| {
+            aidols_state::mul_div(amount_in, fee as Balance, FEE_DIVISOR as Balance)
+                .unwrap_or(amount_in)
+        };
+        Self {
+            total_fee: fee(fees.total_fee),
+            exchange_fee: fee(fees.exchange_fee),
+            referral_fee: fee(fees.referral_fee),
+        }
+    }
+}
+
+/// How a Ref swap got the input tokens
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwapMethod {
+    /// `ft_transfer_call` with swap actions in the message. Ref calls this an
+    /// instant swap, the tokens don't have to be deposited first.
+    Instant,
+    /// `swap`, `swap_by_output`, or `execute_actions` from the trader's Ref deposit
+    Deposited,
+}
+
+/// Gas burnt by a Ref swap. For [`SwapMethod::Instant`] swaps this includes the
+/// token contract's `ft_transfer_call` receipt, but not the `ft_resolve_transfer`
+/// callback or the output transfer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SwapGasStats {
+    pub method: SwapMethod,
+    pub gas_burnt: u64,
+}
+
+/// Contracts that bridge NEAR tokens to other chains, see
+/// [`TradeIndexer::with_bridge_contracts`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum BridgeContract {
+    /// Aurora engine, the `ft_transfer_call` message starts with the recipient's
+    /// EVM address
+    Aurora,
+    /// Any other bridge, the whole `ft_transfer_call` message is reported as the
+    /// destination address
+    Other {
+        account_id: AccountId,
+        chain: String,
+    },
+}
+
+impl BridgeContract {
+    pub fn account_id(&self) -> &str {
+        match self {
+            BridgeContract::Aurora => ref_trade_detection::AURORA_CONTRACT_ID,
+            BridgeContract::Other { account_id, .. } => account_id.as_str(),
+        }
+    }
+
+    pub fn chain(&self) -> &str {
+        match self {
+            BridgeContract::Aurora => "aurora",
+            BridgeContract::Other { chain, .. } => chain,
+        }
+    }
+
+    /// Recipient on the destination chain, `None` if `msg` isn't a valid transfer
+    /// message of this bridge
+    pub fn destination_address(&self, msg: &str) -> Option<String> {
+        match self {
+            BridgeContract::Aurora => {
+                let hex = msg.strip_prefix("0x").unwrap_or(msg).get(..40)?;
+                if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return None;
+                }
+                Some(format!("0x{}", hex.to_lowercase()))
+            }
+            BridgeContract::Other { .. } => Some(msg.to_owned()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct BalanceChangeSwap {
     balance_changes: HashMap<AccountId, i128>,
     pool_swaps: Vec<RawPoolSwap>,
 }
 
+/// Net change of a token below this fraction of its swapped amount is treated as zero
+/// by [`BalanceChangeSwap::is_flash_swap`], in basis points
+pub const FLASH_SWAP_TOLERANCE_BPS: u128 = 10;
+
+impl BalanceChangeSwap {
+    /// Whether the swaps return (almost) everything they took, like a borrow, swap,
+    /// repay sequence, or a cyclic arbitrage. Every token's net change must be within
+    /// [`FLASH_SWAP_TOLERANCE_BPS`] of the total amount of that token put into pools.
+    pub fn is_flash_swap(&self) -> bool {
+        if self.pool_swaps.is_empty() {
+            return false;
+        }
+        self.balance_changes.iter().all(|(token, change)| {
+            let swapped_in = self
+                .pool_swaps
+                .iter()
+                .filter(|swap| swap.token_in == *token)
+                .map(|swap| swap.amount_in)
+                .fold(0, Balance::saturating_add);
+            // Amounts of 24-decimal tokens can be close to u128::MAX
+            aidols_state::mul_div(swapped_in, FLASH_SWAP_TOLERANCE_BPS, 10_000)
+                .is_some_and(|tolerance| change.unsigned_abs() <= tolerance)
+        })
+    }
+
+    /// Tokens along the swap path in order, e.g. `[usdc, wrap.near, intel]` for a
+    /// two-hop swap. If a hop doesn't start with the previous hop's output (a split
+    /// route), its input token is included as well.
+    pub fn route(&self) -> Vec<AccountId> {
+        let mut route: Vec<AccountId> = Vec::with_capacity(self.pool_swaps.len() + 1);
+        for swap in &self.pool_swaps {
+            if route.last() != Some(&swap.token_in) {
+                route.push(swap.token_in.clone());
+            }
+            route.push(swap.token_out.clone());
+        }
+        route
+    }
+}
+
+/// Change of an Aidols pool over a block, see
+/// [`TradeEventHandler::on_aidols_pool_diff`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct AidolsPoolDiff {
+    pub token_hold_delta: i128,
+    pub wnear_hold_delta: i128,
+    pub is_deployed_changed: bool,
+    pub is_tradable_changed: bool,
+}
+
+impl AidolsPoolDiff {
+    pub(crate) fn between(old: &AidolsPoolState, new: &AidolsPoolState) -> Self {
+        Self {
+            token_hold_delta: new.token_hold as i128 - old.token_hold as i128,
+            wnear_hold_delta: new.wnear_hold as i128 - old.wnear_hold as i128,
+            is_deployed_changed: new.is_deployed != old.is_deployed,
+            is_tradable_changed: new.is_tradable != old.is_tradable,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.token_hold_delta == 0
+            && self.wnear_hold_delta == 0
+            && !self.is_deployed_changed
+            && !self.is_tradable_changed
+    }
+}
+
+/// Estimated value locked in a pool after a pool change
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolTvlEstimate {
+    pub pool_id: PoolId,
+    /// In yoctoNEAR, `None` if the pool has no wNEAR side to price it by
+    pub tvl_near: Option<Balance>,
+    /// Set when enabled with [`TradeIndexer::with_usd_oracle`] and wNEAR has a known
+    /// USD price
+    pub tvl_usd: Option<f64>,
+    pub timestamp: u128,
+}
+
+/// First token of a Ref simple pool that `token_validator` rejects. Stable and rated
+/// pools are created by Ref, so their tokens aren't checked.
+pub(crate) async fn invalid_pool_token(
+    token_validator: &dyn TokenValidator,
+    pool: &ref_finance_state::Pool,
+) -> Option<AccountId> {
+    let ref_finance_state::Pool::SimplePool(simple_pool) = pool else {
+        return None;
+    };
+    for token in &simple_pool.token_account_ids {
+        let Ok(token) = token.parse::<AccountId>() else {
+            continue;
+        };
+        if !token_validator.is_valid_token(&token).await {
+            return Some(token);
+        }
+    }
+    None
+}
+
+/// Both sides of a 2-token constant product pool have equal value, so a pool
+/// with wNEAR on one side holds twice its wNEAR reserve
+fn estimate_tvl_near(pool: &ref_finance_state::Pool, wrap_near: &str) -> Option<Balance> {
+    let reserves = pool.reserves()?;
+    if reserves.len() != 2 {
+        return None;
+    }
+    reserves
+        .iter()
+        .find(|(token, _)| *token == wrap_near)
+        .map(|(_, amount)| amount.saturating_mul(2))
+}
+
 #[derive(Debug, PartialEq)]
 pub struct PoolChangeEvent {
     pool_id: PoolId,
@@ -245,14 +1673,92 @@ pub struct PoolChangeEvent {
     block_timestamp_nanosec: u128,
     block_height: BlockHeight,
     pool: PoolType,
+    /// Price of the pool's token in wNEAR, see
+    /// `AidolsPoolState::spot_price_in_wnear`. Only set for Aidols pools.
+    spot_price: Option<u128>,
+    /// The pool has a sampling rate below 1, so other changes of it may have been
+    /// dropped, see [`TradeIndexer::with_sampling_rate`]
+    sampled: bool,
+}
+
+impl PoolChangeEvent {
+    pub fn as_ref_pool(&self) -> Option<&ref_finance_state::Pool> {
+        match &self.pool {
+            PoolType::Ref(pool) => Some(pool),
+            _ => None,
+        }
+    }
+
+    pub fn as_aidols_pool(&self) -> Option<&AidolsPool> {
+        match &self.pool {
+            PoolType::Aidols(pool) => Some(pool),
+            _ => None,
+        }
+    }
 }
 
+/// Gives the event back if it's not a Ref pool
+impl TryFrom<PoolChangeEvent> for ref_finance_state::Pool {
+    type Error = PoolChangeEvent;
+
+    fn try_from(event: PoolChangeEvent) -> Result<Self, Self::Error> {
+        match event.pool {
+            PoolType::Ref(pool) => Ok(pool),
+            _ => Err(event),
+        }
+    }
+}
+
+/// Gives the event back if it's not an Aidols pool
+impl TryFrom<PoolChangeEvent> for AidolsPool {
+    type Error = PoolChangeEvent;
+
+    fn try_from(event: PoolChangeEvent) -> Result<Self, Self::Error> {
+        match event.pool {
+            PoolType::Aidols(pool) => Ok(pool),
+            _ => Err(event),
+        }
+    }
+}
+
+/// Variants are added whenever a new DEX or pool type is supported, and that isn't
+/// considered a breaking change, so matches outside this crate need a wildcard arm.
 #[derive(Debug, PartialEq)]
+#[non_exhaustive]
 pub enum PoolType {
     Ref(ref_finance_state::Pool),
     Aidols(AidolsPool),
 }
 
+/// `system` is the predecessor of receipts created by the protocol, such as refunds,
+/// so events attributed to it aren't real trades.
+pub(crate) fn is_valid_trader(trader: &AccountId, receipt: &TransactionReceipt) -> bool {
+    if trader.as_str().is_empty() || trader == "system" {
+        log::warn!(
+            "Skipping event with trader {trader:?} in receipt {}",
+            receipt.receipt.receipt.receipt_id
+        );
+        false
+    } else {
+        true
+    }
+}
+
+/// Account IDs are lowercase, but tokens in events emitted by contracts aren't
+/// validated, so they're lowercased before parsing to avoid treating one token as
+/// two different ones. Returns `None` if it's not a valid account ID even then.
+pub fn canonicalize_account_id(account_id: &str) -> Option<AccountId> {
+    account_id.to_lowercase().parse().ok()
+}
+
+pub(crate) fn deserialize_canonical_account_id<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<AccountId, D::Error> {
+    let account_id = <String as serde::Deserialize>::deserialize(deserializer)?;
+    canonicalize_account_id(&account_id)
+        .ok_or_else(|| serde::de::Error::custom(format!("Invalid account ID: {account_id}")))
+}
+
 pub(crate) fn find_parent_receipt<'a>(
     transaction: &'a IncompleteTransaction,
     receipt: &TransactionReceipt,
@@ -271,3 +1777,18 @@ pub(crate) fn find_parent_receipt<'a>(
         None
     })
 }
+
+/// Receipts spawned by `receipt` that have already been executed and failed
+pub(crate) fn failed_child_receipts<'a>(
+    transaction: &'a IncompleteTransaction,
+    receipt: &'a TransactionReceipt,
+) -> impl Iterator<Item = &'a TransactionReceipt> {
+    let child_ids = &receipt.receipt.execution_outcome.outcome.receipt_ids;
+    transaction
+        .receipts
+        .iter()
+        .filter_map(|(_, r)| r.as_ref())
+        .filter(move |r| {
+            child_ids.contains(&r.receipt.receipt.receipt_id) && !r.is_successful(false)
+        })
+}
@@ -1,4 +1,9 @@
+use clap::{CommandFactory, Parser};
+use inindexer::near_indexer_primitives::types::BlockHeight;
+use serde_json::json;
+use trade_indexer::noop_handler::NoopHandler;
 use trade_indexer::redis_handler::PushToRedisStream;
+use trade_indexer::{TradeEventHandler, TradeIndexer};
 
 use inindexer::neardata::NeardataProvider;
 use inindexer::{
@@ -6,6 +11,31 @@ use inindexer::{
 };
 use redis::aio::ConnectionManager;
 
+const IS_TESTNET: bool = true;
+
+/// Without a block range, continues from where the last run stopped.
+#[derive(Parser)]
+struct Args {
+    /// First block to index
+    #[arg(long, conflicts_with = "from_date")]
+    start_block: Option<BlockHeight>,
+    /// Last block to index (inclusive)
+    #[arg(long, conflicts_with = "to_date")]
+    end_block: Option<BlockHeight>,
+    /// Start from the first block produced at or after this date (e.g. `2024-05-04` or `2024-05-04T12:00:00Z`)
+    #[arg(long, value_parser = parse_date)]
+    from_date: Option<u64>,
+    /// Stop at the first block produced at or after this date
+    #[arg(long, value_parser = parse_date)]
+    to_date: Option<u64>,
+    /// Archival RPC used to look up block heights for `--from-date` and `--to-date`
+    #[arg(long, default_value = "https://archival-rpc.testnet.near.org")]
+    rpc_url: String,
+    /// Don't push anything to Redis, only print the number of events in each block
+    #[arg(long)]
+    dry_run: bool,
+}
+
 #[tokio::main]
 async fn main() {
     dotenv::dotenv().ok();
@@ -15,16 +45,50 @@ async fn main() {
         .init()
         .unwrap();
 
-    let client = redis::Client::open(
-        std::env::var("REDIS_URL").expect("No $REDIS_URL environment variable set"),
-    )
-    .unwrap();
-    let connection = ConnectionManager::new(client).await.unwrap();
-
-    let mut indexer = trade_indexer::TradeIndexer {
-        handler: PushToRedisStream::new(connection, 100_000).await,
-        is_testnet: true,
+    let args = Args::parse();
+    let rpc = reqwest::Client::new();
+    let start_block = match (args.start_block, args.from_date) {
+        (Some(height), _) => Some(height),
+        (None, Some(timestamp)) => Some(block_height_at(&rpc, &args.rpc_url, timestamp).await),
+        (None, None) => None,
+    };
+    let end_block = match (args.end_block, args.to_date) {
+        (Some(height), _) => Some(height),
+        (None, Some(timestamp)) => Some(block_height_at(&rpc, &args.rpc_url, timestamp).await),
+        (None, None) => None,
     };
+    let range = match (start_block, end_block) {
+        (Some(start_block), Some(end_block)) => {
+            log::info!("Indexing blocks {start_block}..={end_block}");
+            BlockIterator::iterator(start_block..=end_block)
+        }
+        (None, None) => BlockIterator::AutoContinue(AutoContinue::default()),
+        _ => Args::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "Both the start and the end of the block range must be set",
+            )
+            .exit(),
+    };
+
+    if args.dry_run {
+        run(NoopHandler::new(true), range).await;
+    } else {
+        let client = redis::Client::open(
+            std::env::var("REDIS_URL").expect("No $REDIS_URL environment variable set"),
+        )
+        .unwrap();
+        let connection = ConnectionManager::new(client).await.unwrap();
+        run(
+            PushToRedisStream::new(connection, 100_000, IS_TESTNET).await,
+            range,
+        )
+        .await;
+    }
+}
+
+async fn run(handler: impl TradeEventHandler, range: BlockIterator) {
+    let mut indexer = TradeIndexer::new(handler, IS_TESTNET);
 
     let streamer = NeardataProvider::testnet();
 
@@ -32,26 +96,7 @@ async fn main() {
         &mut indexer,
         streamer,
         IndexerOptions {
-            range: if std::env::args().len() > 1 {
-                // For debugging
-                let msg = "Usage: `trade-indexer` or `trade-indexer [start-block] [end-block]`";
-                BlockIterator::iterator(
-                    std::env::args()
-                        .nth(1)
-                        .expect(msg)
-                        .replace(['_', ',', ' ', '.'], "")
-                        .parse()
-                        .expect(msg)
-                        ..=std::env::args()
-                            .nth(2)
-                            .expect(msg)
-                            .replace(['_', ',', ' ', '.'], "")
-                            .parse()
-                            .expect(msg),
-                )
-            } else {
-                BlockIterator::AutoContinue(AutoContinue::default())
-            },
+            range,
             preprocess_transactions: Some(PreprocessTransactionsSettings {
                 prefetch_blocks: if cfg!(debug_assertions) { 0 } else { 100 },
                 postfetch_blocks: 0,
@@ -62,3 +107,103 @@ async fn main() {
     .await
     .expect("Indexer run failed");
 }
+
+/// Parses an ISO 8601 date or date-time into a timestamp in nanoseconds
+fn parse_date(date: &str) -> Result<u64, String> {
+    let timestamp = if let Ok(date_time) = chrono::DateTime::parse_from_rfc3339(date) {
+        date_time.timestamp_nanos_opt()
+    } else if let Ok(date) = chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+        date.and_hms_opt(0, 0, 0)
+            .and_then(|date_time| date_time.and_utc().timestamp_nanos_opt())
+    } else {
+        return Err("Expected a date like 2024-05-04 or 2024-05-04T12:00:00Z".to_string());
+    };
+    timestamp
+        .and_then(|timestamp| u64::try_from(timestamp).ok())
+        .ok_or_else(|| "Date is out of range".to_string())
+}
+
+/// Binary searches for the first block produced at or after `timestamp_nanosec`
+async fn block_height_at(
+    rpc: &reqwest::Client,
+    rpc_url: &str,
+    timestamp_nanosec: u64,
+) -> BlockHeight {
+    let genesis_height = rpc_call(rpc, rpc_url, "EXPERIMENTAL_genesis_config", json!({}))
+        .await
+        .and_then(|genesis| genesis["genesis_height"].as_u64())
+        .expect("Failed to get genesis height");
+    let (latest_height, _) = block_at(rpc, rpc_url, json!({ "finality": "final" }))
+        .await
+        .expect("Failed to get the latest final block");
+
+    let (mut low, mut high) = (genesis_height, latest_height);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        let (height, timestamp) = first_block_from(rpc, rpc_url, middle).await;
+        if timestamp < timestamp_nanosec {
+            low = height + 1;
+        } else {
+            high = middle;
+        }
+    }
+    first_block_from(rpc, rpc_url, low).await.0
+}
+
+/// Height and timestamp of the first block at or after `height`, since some
+/// heights may have no block
+async fn first_block_from(
+    rpc: &reqwest::Client,
+    rpc_url: &str,
+    height: BlockHeight,
+) -> (BlockHeight, u64) {
+    const MAX_SKIPPED_HEIGHTS: u64 = 100;
+    for height in height..height + MAX_SKIPPED_HEIGHTS {
+        if let Some(block) = block_at(rpc, rpc_url, json!({ "block_id": height })).await {
+            return block;
+        }
+    }
+    panic!("No blocks found in {MAX_SKIPPED_HEIGHTS} heights after {height}");
+}
+
+/// Returns `None` if there's no block with these params
+async fn block_at(
+    rpc: &reqwest::Client,
+    rpc_url: &str,
+    params: serde_json::Value,
+) -> Option<(BlockHeight, u64)> {
+    let block = rpc_call(rpc, rpc_url, "block", params).await?;
+    let header = &block["header"];
+    Some((header["height"].as_u64()?, header["timestamp"].as_u64()?))
+}
+
+/// Returns `None` on `UNKNOWN_BLOCK` errors, panics on other errors
+async fn rpc_call(
+    rpc: &reqwest::Client,
+    rpc_url: &str,
+    method: &str,
+    params: serde_json::Value,
+) -> Option<serde_json::Value> {
+    let mut response: serde_json::Value = rpc
+        .post(rpc_url)
+        .json(&json!({
+            "jsonrpc": "2.0",
+            "id": "dontcare",
+            "method": method,
+            "params": params,
+        }))
+        .send()
+        .await
+        .expect("RPC request failed")
+        .json()
+        .await
+        .expect("Invalid RPC response");
+    let error = &response["error"];
+    if !error.is_null() {
+        if error["cause"]["name"] == "UNKNOWN_BLOCK" {
+            return None;
+        }
+        panic!("RPC call {method} failed: {error}");
+    }
+    Some(response["result"].take())
+}
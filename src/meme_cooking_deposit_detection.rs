@@ -9,7 +9,7 @@ use inindexer::{
 };
 use serde::Deserialize;
 
-use crate::{TradeContext, TradeEventHandler};
+use crate::{is_valid_trader, TradeContext, TradeEventHandler};
 
 pub const TESTNET_FACTORY_CONTRACT_ID: &str = "factory.v10.meme-cooking.testnet";
 pub const FACTORY_CONTRACT_ID: &str = "meme-cooking.near";
@@ -55,6 +55,9 @@ pub async fn detect(
                 if deposit.standard != "meme-cooking" || deposit.event != "deposit" {
                     continue;
                 }
+                if !is_valid_trader(&deposit.data.account_id, receipt) {
+                    continue;
+                }
                 handler
                     .on_memecooking_deposit(
                         TradeContext {
@@ -72,6 +75,9 @@ pub async fn detect(
                 if withdraw.standard != "meme-cooking" || withdraw.event != "withdraw" {
                     continue;
                 }
+                if !is_valid_trader(&withdraw.data.account_id, receipt) {
+                    continue;
+                }
                 handler
                     .on_memecooking_withdraw(
                         TradeContext {
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use inindexer::near_indexer_primitives::types::AccountId;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::RwLock;
+
+/// NEP-148 fields that consumers need to display token amounts
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct FtMetadata {
+    pub symbol: String,
+    pub decimals: u8,
+}
+
+/// Failed `ft_metadata` calls aren't retried for this long. Most failures are
+/// contracts that aren't fungible tokens, so retrying on every trade only adds
+/// RPC calls.
+pub const METADATA_RETRY_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// Token metadata fetched through `ft_metadata` RPC calls the first time each
+/// token is seen
+pub struct MetadataCache {
+    rpc: MetadataRpc,
+    metadata: HashMap<AccountId, FtMetadata>,
+    /// When the last call failed, for tokens that don't have metadata
+    failures: HashMap<AccountId, Instant>,
+    retry_after: Duration,
+}
+
+impl MetadataCache {
+    pub fn new(rpc_url: String) -> Self {
+        Self {
+            rpc: MetadataRpc {
+                client: reqwest::Client::new(),
+                rpc_url,
+            },
+            metadata: HashMap::new(),
+            failures: HashMap::new(),
+            retry_after: METADATA_RETRY_AFTER,
+        }
+    }
+
+    /// Overrides [`METADATA_RETRY_AFTER`]
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+
+    pub fn get(&self, token: &AccountId) -> Option<&FtMetadata> {
+        self.metadata.get(token)
+    }
+
+    /// Whether the token isn't cached and its last call didn't fail recently
+    pub fn should_fetch(&self, token: &AccountId) -> bool {
+        !self.metadata.contains_key(token)
+            && self
+                .failures
+                .get(token)
+                .is_none_or(|failed_at| failed_at.elapsed() >= self.retry_after)
+    }
+
+    /// Stores the result of an `ft_metadata` call. Returns the metadata if the
+    /// token wasn't cached before.
+    pub fn insert_result(
+        &mut self,
+        token: &AccountId,
+        result: Result<FtMetadata, String>,
+    ) -> Option<FtMetadata> {
+        match result {
+            Ok(metadata) => {
+                self.failures.remove(token);
+                if self.metadata.contains_key(token) {
+                    return None;
+                }
+                self.metadata.insert(token.clone(), metadata.clone());
+                Some(metadata)
+            }
+            Err(err) => {
+                log::warn!("Failed to fetch metadata of {token}: {err}");
+                self.failures.insert(token.clone(), Instant::now());
+                None
+            }
+        }
+    }
+
+    /// Fetches and caches metadata of a token that wasn't seen before. The lock is
+    /// only held to check and update the cache, not during the call. Returns `None`
+    /// if the token is already cached or the call failed, in which case it's
+    /// retried after [`MetadataCache::with_retry_after`].
+    pub async fn fetch_if_new(cache: &RwLock<Self>, token: &AccountId) -> Option<FtMetadata> {
+        let rpc = {
+            let cache = cache.read().await;
+            if !cache.should_fetch(token) {
+                return None;
+            }
+            cache.rpc.clone()
+        };
+        let result = rpc.fetch(token).await;
+        cache.write().await.insert_result(token, result)
+    }
+}
+
+#[derive(Clone)]
+struct MetadataRpc {
+    client: reqwest::Client,
+    rpc_url: String,
+}
+
+impl MetadataRpc {
+    async fn fetch(&self, token: &AccountId) -> Result<FtMetadata, String> {
+        let response: serde_json::Value = self
+            .client
+            .post(&self.rpc_url)
+            .json(&json!({
+                "jsonrpc": "2.0",
+                "id": "dontcare",
+                "method": "query",
+                "params": {
+                    "request_type": "call_function",
+                    "finality": "final",
+                    "account_id": token,
+                    "method_name": "ft_metadata",
+                    // base64 of "{}"
+                    "args_base64": "e30=",
+                },
+            }))
+            .send()
+            .await
+            .map_err(|err| err.to_string())?
+            .json()
+            .await
+            .map_err(|err| err.to_string())?;
+        if !response["error"].is_null() {
+            return Err(response["error"].to_string());
+        }
+        let result: Vec<u8> = serde_json::from_value(response["result"]["result"].clone())
+            .map_err(|err| format!("Invalid call result: {err}"))?;
+        serde_json::from_slice(&result).map_err(|err| format!("Invalid metadata: {err}"))
+    }
+}
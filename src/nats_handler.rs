@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+
+use crate::meme_cooking_deposit_detection::{DepositEvent, WithdrawEvent};
+use crate::redis_handler::trade_pool_change_event;
+use crate::{
+    BalanceChangeSwap, PoolChangeEvent, PoolId, RawPoolSwap, TradeContext, TradeEventHandler,
+};
+use async_nats::jetstream;
+use async_trait::async_trait;
+use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
+use intear_events::events::trade::liquidity_pool::LiquidityPoolEvent;
+use intear_events::events::trade::memecooking_deposit::MemeCookingDepositEvent;
+use intear_events::events::trade::memecooking_withdraw::MemeCookingWithdrawEvent;
+use intear_events::events::trade::trade_pool::TradePoolEvent;
+use intear_events::events::trade::trade_swap::TradeSwapEvent;
+use serde::Serialize;
+
+const POOL_SWAPS_SUBJECT: &str = "trade.pool_swaps";
+const BALANCE_CHANGES_SUBJECT: &str = "trade.balance_changes";
+const POOL_CHANGES_SUBJECT: &str = "trade.pool_changes";
+const LIQUIDITY_SUBJECT: &str = "trade.liquidity";
+const MEMECOOKING_DEPOSITS_SUBJECT: &str = "trade.memecooking_deposits";
+const MEMECOOKING_WITHDRAWALS_SUBJECT: &str = "trade.memecooking_withdrawals";
+
+/// Publishes events as JSON to one JetStream subject per event type. Messages
+/// are buffered and published when the block is flushed.
+pub struct NatsHandler {
+    jetstream: jetstream::Context,
+    subject_prefix: String,
+    pending: Vec<PendingMessage>,
+}
+
+struct PendingMessage {
+    subject: &'static str,
+    payload: String,
+}
+
+impl NatsHandler {
+    /// `subject_prefix` is prepended as-is to every subject name, e.g. `testnet.`.
+    /// A stream has to be configured on the server to capture the `trade.>` subjects.
+    pub fn new(client: async_nats::Client, subject_prefix: String) -> Self {
+        Self {
+            jetstream: jetstream::new(client),
+            subject_prefix,
+            pending: Vec::new(),
+        }
+    }
+
+    fn add_event(&mut self, subject: &'static str, event: &impl Serialize) {
+        self.pending.push(PendingMessage {
+            subject,
+            payload: serde_json::to_string(event).expect("Failed to serialize event"),
+        });
+    }
+}
+
+#[async_trait]
+impl TradeEventHandler for NatsHandler {
+    async fn on_raw_pool_swap(&mut self, context: TradeContext, swap: RawPoolSwap) {
+        self.add_event(
+            POOL_SWAPS_SUBJECT,
+            &TradePoolEvent {
+                pool: swap.pool,
+                token_in: swap.token_in,
+                token_out: swap.token_out,
+                amount_in: swap.amount_in,
+                amount_out: swap.amount_out,
+                trader: context.trader,
+                block_height: context.block_height,
+                block_timestamp_nanosec: context.block_timestamp_nanosec,
+                transaction_id: context.transaction_id,
+                receipt_id: context.receipt_id,
+            },
+        );
+    }
+
+    async fn on_balance_change_swap(
+        &mut self,
+        context: TradeContext,
+        balance_changes: BalanceChangeSwap,
+    ) {
+        self.add_event(
+            BALANCE_CHANGES_SUBJECT,
+            &TradeSwapEvent {
+                balance_changes: balance_changes.balance_changes,
+                trader: context.trader,
+                block_height: context.block_height,
+                block_timestamp_nanosec: context.block_timestamp_nanosec,
+                transaction_id: context.transaction_id,
+                receipt_id: context.receipt_id,
+            },
+        );
+    }
+
+    async fn on_pool_change(&mut self, event: PoolChangeEvent) {
+        if let Some(event) = trade_pool_change_event(event) {
+            self.add_event(POOL_CHANGES_SUBJECT, &event);
+        }
+    }
+
+    async fn on_memecooking_deposit(&mut self, context: TradeContext, deposit: DepositEvent) {
+        self.add_event(
+            MEMECOOKING_DEPOSITS_SUBJECT,
+            &MemeCookingDepositEvent {
+                meme_id: deposit.meme_id,
+                amount: deposit.amount,
+                protocol_fee: deposit.protocol_fee,
+                referrer: deposit.referrer,
+                referrer_fee: deposit.referrer_fee,
+                trader: context.trader,
+                block_height: context.block_height,
+                block_timestamp_nanosec: context.block_timestamp_nanosec,
+                transaction_id: context.transaction_id,
+                receipt_id: context.receipt_id,
+            },
+        );
+    }
+
+    async fn on_memecooking_withdraw(&mut self, context: TradeContext, withdraw: WithdrawEvent) {
+        self.add_event(
+            MEMECOOKING_WITHDRAWALS_SUBJECT,
+            &MemeCookingWithdrawEvent {
+                meme_id: withdraw.meme_id,
+                amount: withdraw.amount,
+                fee: withdraw.fee,
+                trader: context.trader,
+                block_height: context.block_height,
+                block_timestamp_nanosec: context.block_timestamp_nanosec,
+                transaction_id: context.transaction_id,
+                receipt_id: context.receipt_id,
+            },
+        );
+    }
+
+    async fn on_liquidity_pool(
+        &mut self,
+        context: TradeContext,
+        pool_id: PoolId,
+        tokens: HashMap<AccountId, i128>,
+    ) {
+        self.add_event(
+            LIQUIDITY_SUBJECT,
+            &LiquidityPoolEvent {
+                pool: pool_id,
+                tokens,
+                provider_account_id: context.trader,
+                block_height: context.block_height,
+                block_timestamp_nanosec: context.block_timestamp_nanosec,
+                transaction_id: context.transaction_id,
+                receipt_id: context.receipt_id,
+            },
+        );
+    }
+
+    async fn flush_events(&mut self, block_height: BlockHeight) {
+        // Publish everything first and then wait for the acks, so that the block's
+        // messages aren't sent one round trip at a time
+        let mut acks = Vec::with_capacity(self.pending.len());
+        for message in self.pending.drain(..) {
+            let subject = format!("{}{}", self.subject_prefix, message.subject);
+            match self
+                .jetstream
+                .publish(subject, message.payload.into())
+                .await
+            {
+                Ok(ack) => acks.push(ack),
+                Err(err) => panic!("Failed to publish NATS message in block {block_height}: {err}"),
+            }
+        }
+        for ack in acks {
+            if let Err(err) = ack.await {
+                panic!("NATS message in block {block_height} wasn't acknowledged: {err}");
+            }
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+
+use crate::meme_cooking_deposit_detection::{DepositEvent, WithdrawEvent};
+use crate::orderly_transfer_detection::ProtocolTransferEvent;
+use crate::{
+    BalanceChangeSwap, PoolChangeEvent, PoolId, RawPoolSwap, TradeContext, TradeEventHandler,
+};
+use async_trait::async_trait;
+use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
+
+/// Drops all events. If `report_counts` is set, prints how many events of each
+/// kind were detected in every block instead.
+#[derive(Default)]
+pub struct NoopHandler {
+    report_counts: bool,
+    counts: EventCounts,
+}
+
+#[derive(Default)]
+struct EventCounts {
+    pool_swaps: usize,
+    balance_change_swaps: usize,
+    pool_changes: usize,
+    memecooking_deposits: usize,
+    memecooking_withdraws: usize,
+    liquidity_pool_events: usize,
+    protocol_deposits: usize,
+    protocol_withdrawals: usize,
+}
+
+impl NoopHandler {
+    pub fn new(report_counts: bool) -> Self {
+        Self {
+            report_counts,
+            counts: EventCounts::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl TradeEventHandler for NoopHandler {
+    async fn on_raw_pool_swap(&mut self, _context: TradeContext, _swap: RawPoolSwap) {
+        self.counts.pool_swaps += 1;
+    }
+
+    async fn on_balance_change_swap(
+        &mut self,
+        _context: TradeContext,
+        _balance_changes: BalanceChangeSwap,
+    ) {
+        self.counts.balance_change_swaps += 1;
+    }
+
+    async fn on_pool_change(&mut self, _pool: PoolChangeEvent) {
+        self.counts.pool_changes += 1;
+    }
+
+    async fn on_memecooking_deposit(&mut self, _context: TradeContext, _deposit: DepositEvent) {
+        self.counts.memecooking_deposits += 1;
+    }
+
+    async fn on_memecooking_withdraw(&mut self, _context: TradeContext, _withdraw: WithdrawEvent) {
+        self.counts.memecooking_withdraws += 1;
+    }
+
+    async fn on_liquidity_pool(
+        &mut self,
+        _context: TradeContext,
+        _pool_id: PoolId,
+        _tokens: HashMap<AccountId, i128>,
+    ) {
+        self.counts.liquidity_pool_events += 1;
+    }
+
+    async fn on_deposit_to_protocol(
+        &mut self,
+        _context: TradeContext,
+        _transfer: ProtocolTransferEvent,
+    ) {
+        self.counts.protocol_deposits += 1;
+    }
+
+    async fn on_withdrawal_from_protocol(
+        &mut self,
+        _context: TradeContext,
+        _transfer: ProtocolTransferEvent,
+    ) {
+        self.counts.protocol_withdrawals += 1;
+    }
+
+    async fn flush_events(&mut self, block_height: BlockHeight) {
+        let counts = std::mem::take(&mut self.counts);
+        if self.report_counts {
+            println!(
+                "Block {block_height}: {} pool swaps, {} swaps, {} pool changes, {} meme cooking deposits, {} meme cooking withdrawals, {} liquidity events, {} protocol deposits, {} protocol withdrawals",
+                counts.pool_swaps,
+                counts.balance_change_swaps,
+                counts.pool_changes,
+                counts.memecooking_deposits,
+                counts.memecooking_withdraws,
+                counts.liquidity_pool_events,
+                counts.protocol_deposits,
+                counts.protocol_withdrawals,
+            );
+        }
+    }
+}
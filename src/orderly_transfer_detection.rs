@@ -0,0 +1,162 @@
+use inindexer::{
+    near_indexer_primitives::{
+        types::{AccountId, Balance},
+        views::{ActionView, ExecutionStatusView, ReceiptEnumView},
+        StreamerMessage,
+    },
+    near_utils::dec_format,
+    IncompleteTransaction, TransactionReceipt,
+};
+use serde::Deserialize;
+
+use crate::{is_valid_trader, TradeContext, TradeEventHandler, NATIVE_NEAR_TOKEN_ID};
+
+pub const ORDERLY_ASSET_MANAGER_CONTRACT_ID: &str = "asset-manager.orderly-network.near";
+
+/// Funds moved between a user's wallet and a trading protocol's vault
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtocolTransferEvent {
+    pub trader: AccountId,
+    pub token: AccountId,
+    pub amount: Balance,
+    pub protocol: &'static str,
+}
+
+pub async fn detect(
+    receipt: &TransactionReceipt,
+    transaction: &IncompleteTransaction,
+    block: &StreamerMessage,
+    handler: &mut impl TradeEventHandler,
+    is_testnet: bool,
+) {
+    if is_testnet {
+        return;
+    }
+    if !receipt.is_successful(false)
+        || receipt.receipt.receipt.receiver_id != ORDERLY_ASSET_MANAGER_CONTRACT_ID
+    {
+        return;
+    }
+    let ReceiptEnumView::Action { actions, .. } = &receipt.receipt.receipt.receipt else {
+        return;
+    };
+    let predecessor_id = &receipt.receipt.receipt.predecessor_id;
+    for action in actions {
+        let ActionView::FunctionCall {
+            method_name,
+            args,
+            deposit,
+            ..
+        } = action
+        else {
+            continue;
+        };
+        let (transfer, is_deposit) = match method_name.as_str() {
+            "ft_on_transfer" => {
+                let Ok(args) = serde_json::from_slice::<FtOnTransferArgs>(args) else {
+                    continue;
+                };
+                // The token contract refunds what the asset manager returns as unused,
+                // so only the rest is deposited
+                let Some(unused_amount) = ft_on_transfer_unused_amount(receipt, transaction) else {
+                    log::debug!(
+                        "Unknown result of Orderly deposit in receipt {}, skipping",
+                        receipt.receipt.receipt.receipt_id
+                    );
+                    continue;
+                };
+                let amount = args.amount.saturating_sub(unused_amount);
+                if amount == 0 {
+                    continue;
+                }
+                let transfer = ProtocolTransferEvent {
+                    trader: args.sender_id,
+                    token: predecessor_id.clone(),
+                    amount,
+                    protocol: "orderly",
+                };
+                (transfer, true)
+            }
+            "deposit" => {
+                if *deposit == 0 {
+                    continue;
+                }
+                let transfer = ProtocolTransferEvent {
+                    trader: predecessor_id.clone(),
+                    token: NATIVE_NEAR_TOKEN_ID.parse().unwrap(),
+                    amount: *deposit,
+                    protocol: "orderly",
+                };
+                (transfer, true)
+            }
+            "user_request_withdraw" => {
+                let Ok(args) = serde_json::from_slice::<UserRequestWithdrawArgs>(args) else {
+                    continue;
+                };
+                let transfer = ProtocolTransferEvent {
+                    trader: predecessor_id.clone(),
+                    token: args.token,
+                    amount: args.amount,
+                    protocol: "orderly",
+                };
+                (transfer, false)
+            }
+            _ => continue,
+        };
+        if !is_valid_trader(&transfer.trader, receipt) {
+            continue;
+        }
+        let context = TradeContext {
+            trader: transfer.trader.clone(),
+            block_height: block.block.header.height,
+            block_timestamp_nanosec: block.block.header.timestamp_nanosec as u128,
+            transaction_id: transaction.transaction.transaction.hash,
+            receipt_id: receipt.receipt.receipt.receipt_id,
+        };
+        if is_deposit {
+            handler.on_deposit_to_protocol(context, transfer).await;
+        } else {
+            handler.on_withdrawal_from_protocol(context, transfer).await;
+        }
+    }
+}
+
+/// Unused amount returned by `ft_on_transfer` as a U128 string, either directly or
+/// by the receipt it returned, if that receipt is already known
+fn ft_on_transfer_unused_amount(
+    receipt: &TransactionReceipt,
+    transaction: &IncompleteTransaction,
+) -> Option<Balance> {
+    let value = match &receipt.receipt.execution_outcome.outcome.status {
+        ExecutionStatusView::SuccessValue(value) => value,
+        ExecutionStatusView::SuccessReceiptId(receipt_id) => {
+            let returned_receipt = transaction
+                .receipts
+                .iter()
+                .filter_map(|(_, r)| r.as_ref())
+                .find(|r| r.receipt.receipt.receipt_id == *receipt_id)?;
+            let ExecutionStatusView::SuccessValue(value) =
+                &returned_receipt.receipt.execution_outcome.outcome.status
+            else {
+                return None;
+            };
+            value
+        }
+        _ => return None,
+    };
+    serde_json::from_slice::<String>(value).ok()?.parse().ok()
+}
+
+#[derive(Deserialize, Debug)]
+struct FtOnTransferArgs {
+    sender_id: AccountId,
+    #[serde(with = "dec_format")]
+    amount: Balance,
+}
+
+#[derive(Deserialize, Debug)]
+struct UserRequestWithdrawArgs {
+    token: AccountId,
+    #[serde(with = "dec_format")]
+    amount: Balance,
+}
@@ -0,0 +1,29 @@
+use inindexer::near_indexer_primitives::types::AccountId;
+
+/// Pool ID split into the DEX it belongs to and the DEX's own pool identifier.
+/// Inverse of `create_ref_pool_id` and `create_aidols_pool_id`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParsedPoolId {
+    Ref(u64),
+    Aidols(AccountId),
+}
+
+impl ParsedPoolId {
+    /// Same names as used in [`crate::IndexerStats`]
+    pub fn dex_name(&self) -> &str {
+        match self {
+            ParsedPoolId::Ref(_) => "ref",
+            ParsedPoolId::Aidols(_) => "aidols",
+        }
+    }
+}
+
+pub fn parse_pool_id(pool_id: &str) -> Option<ParsedPoolId> {
+    if let Some(id) = pool_id.strip_prefix("REF-") {
+        id.parse().ok().map(ParsedPoolId::Ref)
+    } else if let Some(token_id) = pool_id.strip_prefix("AIDOLS-") {
+        token_id.parse().ok().map(ParsedPoolId::Aidols)
+    } else {
+        None
+    }
+}
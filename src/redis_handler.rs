@@ -1,14 +1,17 @@
 use std::collections::HashMap;
 
 use crate::meme_cooking_deposit_detection::{DepositEvent, WithdrawEvent};
+use crate::pool_id_utils::{parse_pool_id, ParsedPoolId};
 use crate::ref_finance_state;
+use crate::ref_trade_detection::{TESTNET_WRAP_NEAR_CONTRACT_ID, WRAP_NEAR_CONTRACT_ID};
 use crate::{
     BalanceChangeSwap, PoolChangeEvent, PoolId, PoolType, RawPoolSwap, TradeContext,
     TradeEventHandler,
 };
 use async_trait::async_trait;
 use inevents_redis::RedisEventStream;
-use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
+use inindexer::near_indexer_primitives::types::{AccountId, Balance, BlockHeight};
+use inindexer::near_indexer_primitives::CryptoHash;
 use intear_events::events::trade::liquidity_pool::LiquidityPoolEvent;
 use intear_events::events::trade::memecooking_deposit::MemeCookingDepositEvent;
 use intear_events::events::trade::memecooking_withdraw::MemeCookingWithdrawEvent;
@@ -19,6 +22,155 @@ use intear_events::events::trade::trade_pool_change::{
 };
 use intear_events::events::trade::trade_swap::TradeSwapEvent;
 use redis::aio::ConnectionManager;
+use serde::{Deserialize, Serialize};
+
+/// Sorted set of pools scored by their wNEAR-denominated volume over the last 24 hours.
+const VOLUME_LEADERBOARD_KEY: &str = "trade_volume_by_pool_24h";
+/// Per-hour sorted sets that are unioned into [`VOLUME_LEADERBOARD_KEY`]. Sorted set
+/// members can't expire individually, so the rolling window is built from buckets
+/// that expire on their own.
+const VOLUME_BUCKET_KEY_PREFIX: &str = "trade_volume_by_pool_1h";
+const VOLUME_BUCKET_TTL_SECONDS: u64 = 25 * 60 * 60;
+const NANOSEC_PER_HOUR: u128 = 60 * 60 * 1_000_000_000;
+/// Hashes of cumulative fees collected by each DEX, keyed by token account ID
+const PROTOCOL_FEES_KEY_PREFIX: &str = "trade:protocol_fees";
+/// DEXes that report the fees charged on each swap. Ref fees are only known with
+/// [`crate::TradeIndexer::with_fee_breakdown`].
+const FEE_REPORTING_DEXES: &[&str] = &["aidols", "ref"];
+/// Sorted sets of candles of each pool, `trade:candle:<interval>:<pool id>`, scored
+/// by the candle's start timestamp in seconds
+const CANDLE_KEY_PREFIX: &str = "trade:candle";
+/// Merges a trade into the candle starting at ARGV[1], or starts a new one.
+/// Members are JSON-encoded [`Candle`]s.
+const UPDATE_CANDLE_SCRIPT: &str = r#"
+local start = tonumber(ARGV[1])
+local price = tonumber(ARGV[2])
+local volume = tonumber(ARGV[3])
+local existing = redis.call('ZRANGEBYSCORE', KEYS[1], start, start)
+local candle
+if #existing > 0 then
+    candle = cjson.decode(existing[1])
+    candle.high = math.max(candle.high, price)
+    candle.low = math.min(candle.low, price)
+    candle.close = price
+    candle.volume = candle.volume + volume
+    redis.call('ZREMRANGEBYSCORE', KEYS[1], start, start)
+else
+    candle = { timestamp = start, open = price, high = price, low = price, close = price, volume = volume }
+end
+redis.call('ZADD', KEYS[1], start, cjson.encode(candle))
+"#;
+const REFERRAL_STREAM_ID: &str = "trade_referral";
+const UNKNOWN_POOL_CHANGE_STREAM_ID: &str = "trade_pool_change_unknown";
+const REF_DEPOSIT_STREAM_ID: &str = "trade_ref_deposits";
+const REF_WITHDRAWAL_STREAM_ID: &str = "trade_ref_withdrawals";
+const POOL_SWAP_USD_STREAM_ID: &str = "trade_pool_usd";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CandleInterval {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl CandleInterval {
+    pub const ALL: [CandleInterval; 3] = [
+        CandleInterval::Minute,
+        CandleInterval::Hour,
+        CandleInterval::Day,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CandleInterval::Minute => "1m",
+            CandleInterval::Hour => "1h",
+            CandleInterval::Day => "1d",
+        }
+    }
+
+    fn seconds(&self) -> u64 {
+        match self {
+            CandleInterval::Minute => 60,
+            CandleInterval::Hour => 60 * 60,
+            CandleInterval::Day => 24 * 60 * 60,
+        }
+    }
+}
+
+/// OHLCV candle of a pool with wNEAR on one side. Prices are in wNEAR per token
+/// (raw amounts, not adjusted for decimals), volume is in yoctoNEAR.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Candle {
+    /// Start of the candle, in seconds
+    pub timestamp: u64,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: f64,
+}
+
+/// Change of a Ref pool whose type this indexer can't deserialize, so that
+/// consumers know the pool changed even though its state isn't in
+/// `trade_pool_change`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UnknownPoolChangeEvent {
+    pub pool_id: PoolId,
+    /// Always `"unknown"`
+    pub pool_type: String,
+    pub discriminant: u8,
+    pub block_height: BlockHeight,
+    pub block_timestamp_nanosec: u128,
+    pub receipt_id: CryptoHash,
+}
+
+/// A swap made through a referral link. Only Aidols swaps are included, Ref swaps
+/// with a `referral_id` aren't.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReferralEvent {
+    pub referrer: AccountId,
+    pub referee: AccountId,
+    pub token_bought: AccountId,
+    pub amount_bought: Balance,
+    pub fee_token: AccountId,
+    /// None if the DEX doesn't report the referrer's part of the fee, which Aidols
+    /// currently doesn't
+    pub fee_amount: Option<Balance>,
+    pub dex: String,
+    pub block_height: BlockHeight,
+    pub block_timestamp_nanosec: u128,
+    pub transaction_id: CryptoHash,
+    pub receipt_id: CryptoHash,
+}
+
+/// Tokens deposited to or withdrawn from a trader's internal Ref balance, used by
+/// both `trade_ref_deposits` and `trade_ref_withdrawals`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RefDepositEvent {
+    pub trader: AccountId,
+    pub token: AccountId,
+    pub amount: Balance,
+    pub block_height: BlockHeight,
+    pub block_timestamp_nanosec: u128,
+    pub transaction_id: CryptoHash,
+    pub receipt_id: CryptoHash,
+}
+
+/// USD value of a pool swap, for swaps where the indexer knows the price of at
+/// least one of the tokens, see [`crate::TradeIndexer::with_usd_oracle`]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PoolSwapUsdEvent {
+    pub pool: PoolId,
+    pub token_in: AccountId,
+    pub token_out: AccountId,
+    pub amount_in_usd: Option<f64>,
+    pub amount_out_usd: Option<f64>,
+    pub trader: AccountId,
+    pub block_height: BlockHeight,
+    pub block_timestamp_nanosec: u128,
+    pub transaction_id: CryptoHash,
+    pub receipt_id: CryptoHash,
+}
 
 pub struct PushToRedisStream {
     pool_stream: RedisEventStream<TradePoolEvent>,
@@ -27,38 +179,287 @@ pub struct PushToRedisStream {
     meme_cooking_deposit_stream: RedisEventStream<MemeCookingDepositEvent>,
     meme_cooking_withdraw_stream: RedisEventStream<MemeCookingWithdrawEvent>,
     liquidity_pool_stream: RedisEventStream<LiquidityPoolEvent>,
+    referral_stream: RedisEventStream<ReferralEvent>,
+    unknown_pool_change_stream: RedisEventStream<UnknownPoolChangeEvent>,
+    ref_deposit_stream: RedisEventStream<RefDepositEvent>,
+    ref_withdrawal_stream: RedisEventStream<RefDepositEvent>,
+    pool_swap_usd_stream: RedisEventStream<PoolSwapUsdEvent>,
     max_stream_size: usize,
+    connection: ConnectionManager,
+    /// wNEAR volumes and candles are only tracked for pools with wNEAR on the
+    /// indexed network
+    is_testnet: bool,
+    keys: RedisKeys,
+    /// wNEAR volume of each pool in the current block, keyed by (hour, pool id)
+    pending_pool_volumes: HashMap<(u128, PoolId), Balance>,
+    /// Fees collected in the current block, keyed by (dex, token)
+    pending_protocol_fees: HashMap<(&'static str, AccountId), Balance>,
+    /// Trades in the current block in order, as (pool id, timestamp in seconds,
+    /// price, wNEAR volume)
+    pending_candle_trades: Vec<(PoolId, u64, f64, f64)>,
 }
 
 impl PushToRedisStream {
-    pub async fn new(connection: ConnectionManager, max_stream_size: usize) -> Self {
+    /// Stream names and all other keys are prefixed with `$REDIS_STREAM_PREFIX` if
+    /// it's set, so that multiple indexers can share one Redis server
+    pub async fn new(
+        connection: ConnectionManager,
+        max_stream_size: usize,
+        is_testnet: bool,
+    ) -> Self {
+        let keys = RedisKeys::from_env();
         Self {
-            pool_stream: RedisEventStream::new(connection.clone(), TradePoolEvent::ID.to_string()),
-            swap_stream: RedisEventStream::new(connection.clone(), TradeSwapEvent::ID.to_string()),
+            pool_stream: RedisEventStream::new(connection.clone(), keys.stream(TradePoolEvent::ID)),
+            swap_stream: RedisEventStream::new(connection.clone(), keys.stream(TradeSwapEvent::ID)),
             pool_change_stream: RedisEventStream::new(
                 connection.clone(),
-                TradePoolChangeEvent::ID.to_string(),
+                keys.stream(TradePoolChangeEvent::ID),
             ),
             meme_cooking_deposit_stream: RedisEventStream::new(
                 connection.clone(),
-                MemeCookingDepositEvent::ID.to_string(),
+                keys.stream(MemeCookingDepositEvent::ID),
             ),
             meme_cooking_withdraw_stream: RedisEventStream::new(
                 connection.clone(),
-                MemeCookingWithdrawEvent::ID.to_string(),
+                keys.stream(MemeCookingWithdrawEvent::ID),
             ),
             liquidity_pool_stream: RedisEventStream::new(
                 connection.clone(),
-                LiquidityPoolEvent::ID.to_string(),
+                keys.stream(LiquidityPoolEvent::ID),
+            ),
+            referral_stream: RedisEventStream::new(
+                connection.clone(),
+                keys.stream(REFERRAL_STREAM_ID),
+            ),
+            unknown_pool_change_stream: RedisEventStream::new(
+                connection.clone(),
+                keys.stream(UNKNOWN_POOL_CHANGE_STREAM_ID),
+            ),
+            ref_deposit_stream: RedisEventStream::new(
+                connection.clone(),
+                keys.stream(REF_DEPOSIT_STREAM_ID),
+            ),
+            ref_withdrawal_stream: RedisEventStream::new(
+                connection.clone(),
+                keys.stream(REF_WITHDRAWAL_STREAM_ID),
+            ),
+            pool_swap_usd_stream: RedisEventStream::new(
+                connection.clone(),
+                keys.stream(POOL_SWAP_USD_STREAM_ID),
             ),
             max_stream_size,
+            connection,
+            is_testnet,
+            keys,
+            pending_pool_volumes: HashMap::new(),
+            pending_protocol_fees: HashMap::new(),
+            pending_candle_trades: Vec::new(),
+        }
+    }
+}
+
+impl PushToRedisStream {
+    fn wrap_near_contract_id(&self) -> &'static str {
+        if self.is_testnet {
+            TESTNET_WRAP_NEAR_CONTRACT_ID
+        } else {
+            WRAP_NEAR_CONTRACT_ID
+        }
+    }
+
+    async fn flush_volume_leaderboard(&mut self) {
+        let Some(latest_hour) = self
+            .pending_pool_volumes
+            .keys()
+            .map(|(hour, _)| *hour)
+            .max()
+        else {
+            return;
+        };
+        let mut pipe = redis::pipe();
+        for ((hour, pool_id), volume) in self.pending_pool_volumes.drain() {
+            let key = self.keys.volume_bucket(hour);
+            pipe.cmd("ZADD")
+                .arg(&key)
+                .arg("INCR")
+                .arg(volume as f64)
+                .arg(pool_id)
+                .ignore();
+            pipe.cmd("EXPIRE")
+                .arg(&key)
+                .arg(VOLUME_BUCKET_TTL_SECONDS)
+                .ignore();
+        }
+        let buckets = (latest_hour.saturating_sub(23)..=latest_hour)
+            .map(|hour| self.keys.volume_bucket(hour))
+            .collect::<Vec<_>>();
+        pipe.cmd("ZUNIONSTORE")
+            .arg(self.keys.volume_leaderboard())
+            .arg(buckets.len())
+            .arg(buckets)
+            .ignore();
+        pipe.query_async::<_, ()>(&mut self.connection)
+            .await
+            .expect("Failed to update pool volume leaderboard");
+    }
+
+    async fn flush_protocol_fees(&mut self) {
+        if self.pending_protocol_fees.is_empty() {
+            return;
+        }
+        let mut pipe = redis::pipe();
+        for ((dex, token), fee) in self.pending_protocol_fees.drain() {
+            pipe.cmd("HINCRBYFLOAT")
+                .arg(self.keys.protocol_fees(dex))
+                .arg(token.as_str())
+                .arg(fee as f64)
+                .ignore();
+        }
+        pipe.query_async::<_, ()>(&mut self.connection)
+            .await
+            .expect("Failed to update protocol fees");
+    }
+
+    async fn flush_candles(&mut self) {
+        if self.pending_candle_trades.is_empty() {
+            return;
         }
+        let mut pipe = redis::pipe();
+        for (pool_id, timestamp, price, volume) in self.pending_candle_trades.drain(..) {
+            for interval in CandleInterval::ALL {
+                pipe.cmd("EVAL")
+                    .arg(UPDATE_CANDLE_SCRIPT)
+                    .arg(1)
+                    .arg(self.keys.candle(&pool_id, interval))
+                    .arg(timestamp - timestamp % interval.seconds())
+                    .arg(price)
+                    .arg(volume)
+                    .ignore();
+            }
+        }
+        pipe.query_async::<_, ()>(&mut self.connection)
+            .await
+            .expect("Failed to update candles");
+    }
+
+    /// All candles of a pool with the given interval, oldest first
+    pub async fn get_candles(&mut self, pool_id: &str, interval: CandleInterval) -> Vec<Candle> {
+        let candles: Vec<String> = redis::cmd("ZRANGEBYSCORE")
+            .arg(self.keys.candle(pool_id, interval))
+            .arg("-inf")
+            .arg("+inf")
+            .query_async(&mut self.connection)
+            .await
+            .expect("Failed to read candles");
+        candles
+            .iter()
+            .filter_map(|candle| serde_json::from_str(candle).ok())
+            .collect()
+    }
+
+    /// Total fees collected by each DEX since tracking started, by token account ID
+    pub async fn cumulative_fees(&mut self) -> HashMap<String, HashMap<String, f64>> {
+        let mut fees = HashMap::new();
+        for dex in FEE_REPORTING_DEXES {
+            let dex_fees: HashMap<String, f64> = redis::cmd("HGETALL")
+                .arg(self.keys.protocol_fees(dex))
+                .query_async(&mut self.connection)
+                .await
+                .expect("Failed to read protocol fees");
+            fees.insert(dex.to_string(), dex_fees);
+        }
+        fees
+    }
+}
+
+/// Names of all streams and keys the handler writes, with the same prefix
+pub(crate) struct RedisKeys {
+    prefix: String,
+}
+
+impl RedisKeys {
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(std::env::var("REDIS_STREAM_PREFIX").unwrap_or_default())
+    }
+
+    pub fn stream(&self, event_id: &str) -> String {
+        format!("{}{event_id}", self.prefix)
+    }
+
+    pub fn volume_leaderboard(&self) -> String {
+        format!("{}{VOLUME_LEADERBOARD_KEY}", self.prefix)
+    }
+
+    pub fn volume_bucket(&self, hour: u128) -> String {
+        format!("{}{VOLUME_BUCKET_KEY_PREFIX}:{hour}", self.prefix)
+    }
+
+    pub fn protocol_fees(&self, dex: &str) -> String {
+        format!("{}{PROTOCOL_FEES_KEY_PREFIX}:{dex}", self.prefix)
+    }
+
+    pub fn candle(&self, pool_id: &str, interval: CandleInterval) -> String {
+        format!(
+            "{}{CANDLE_KEY_PREFIX}:{}:{pool_id}",
+            self.prefix,
+            interval.name()
+        )
     }
 }
 
 #[async_trait]
 impl TradeEventHandler for PushToRedisStream {
     async fn on_raw_pool_swap(&mut self, context: TradeContext, swap: RawPoolSwap) {
+        let wrap_near = self.wrap_near_contract_id();
+        let near_volume = if swap.token_in == wrap_near {
+            Some((swap.amount_in, swap.amount_out))
+        } else if swap.token_out == wrap_near {
+            Some((swap.amount_out, swap.amount_in))
+        } else {
+            None
+        };
+        if let Some((near_volume, token_amount)) = near_volume {
+            let hour = context.block_timestamp_nanosec / NANOSEC_PER_HOUR;
+            *self
+                .pending_pool_volumes
+                .entry((hour, swap.pool.clone()))
+                .or_insert(0) += near_volume;
+            if token_amount != 0 {
+                self.pending_candle_trades.push((
+                    swap.pool.clone(),
+                    (context.block_timestamp_nanosec / 1_000_000_000) as u64,
+                    near_volume as f64 / token_amount as f64,
+                    near_volume as f64,
+                ));
+            }
+        }
+        // Only Ref swaps have a fee breakdown, Aidols fees come as commissions
+        if let Some(fee_breakdown) = swap.fee_breakdown() {
+            *self
+                .pending_protocol_fees
+                .entry(("ref", swap.token_in.clone()))
+                .or_insert(0) += fee_breakdown.total_fee;
+        }
+        if swap.amount_in_usd().is_some() || swap.amount_out_usd().is_some() {
+            self.pool_swap_usd_stream.add_event(PoolSwapUsdEvent {
+                pool: swap.pool.clone(),
+                token_in: swap.token_in.clone(),
+                token_out: swap.token_out.clone(),
+                amount_in_usd: swap.amount_in_usd(),
+                amount_out_usd: swap.amount_out_usd(),
+                trader: context.trader.clone(),
+                block_height: context.block_height,
+                block_timestamp_nanosec: context.block_timestamp_nanosec,
+                transaction_id: context.transaction_id,
+                receipt_id: context.receipt_id,
+            });
+        }
         self.pool_stream.add_event(TradePoolEvent {
             pool: swap.pool,
             token_in: swap.token_in,
@@ -78,6 +479,28 @@ impl TradeEventHandler for PushToRedisStream {
         context: TradeContext,
         balance_changes: BalanceChangeSwap,
     ) {
+        for swap in &balance_changes.pool_swaps {
+            let Some(referrer) = &swap.referral else {
+                continue;
+            };
+            // Aidols fees are always in wNEAR
+            let Some(pool_id @ ParsedPoolId::Aidols(_)) = parse_pool_id(&swap.pool) else {
+                continue;
+            };
+            self.referral_stream.add_event(ReferralEvent {
+                referrer: referrer.clone(),
+                referee: context.trader.clone(),
+                token_bought: swap.token_out.clone(),
+                amount_bought: swap.amount_out,
+                fee_token: self.wrap_near_contract_id().parse().unwrap(),
+                fee_amount: swap.referral_commission,
+                dex: pool_id.dex_name().to_owned(),
+                block_height: context.block_height,
+                block_timestamp_nanosec: context.block_timestamp_nanosec,
+                transaction_id: context.transaction_id,
+                receipt_id: context.receipt_id,
+            });
+        }
         self.swap_stream.add_event(TradeSwapEvent {
             balance_changes: balance_changes.balance_changes,
             trader: context.trader,
@@ -89,93 +512,21 @@ impl TradeEventHandler for PushToRedisStream {
     }
 
     async fn on_pool_change(&mut self, event: PoolChangeEvent) {
-        self.pool_change_stream.add_event(TradePoolChangeEvent {
-            pool_id: event.pool_id.clone(),
-            pool: match event.pool {
-                PoolType::Ref(pool) => {
-                    intear_events::events::trade::trade_pool_change::PoolType::Ref(match pool {
-                        ref_finance_state::Pool::SimplePool(pool) => {
-                            RefPool::SimplePool(RefSimplePool {
-                                token_account_ids: pool
-                                    .token_account_ids
-                                    .into_iter()
-                                    .map(|account_id| account_id.parse().unwrap())
-                                    .collect(),
-                                amounts: pool.amounts,
-                                volumes: pool
-                                    .volumes
-                                    .into_iter()
-                                    .map(|volume| RefSwapVolume {
-                                        input: volume.input,
-                                        output: volume.output,
-                                    })
-                                    .collect(),
-                                total_fee: pool.total_fee,
-                                exchange_fee: pool.exchange_fee,
-                                referral_fee: pool.referral_fee,
-                                shares_total_supply: pool.shares_total_supply,
-                            })
-                        }
-                        ref_finance_state::Pool::StableSwapPool(pool) => {
-                            RefPool::StableSwapPool(RefStableSwapPool {
-                                token_account_ids: pool
-                                    .token_account_ids
-                                    .into_iter()
-                                    .map(|account_id| account_id.parse().unwrap())
-                                    .collect(),
-                                token_decimals: pool.token_decimals,
-                                c_amounts: pool.c_amounts,
-                                volumes: pool
-                                    .volumes
-                                    .into_iter()
-                                    .map(|volume| RefSwapVolume {
-                                        input: volume.input,
-                                        output: volume.output,
-                                    })
-                                    .collect(),
-                                total_fee: pool.total_fee,
-                                shares_total_supply: pool.shares_total_supply,
-                                init_amp_factor: pool.init_amp_factor,
-                                target_amp_factor: pool.target_amp_factor,
-                                init_amp_time: pool.init_amp_time,
-                                stop_amp_time: pool.stop_amp_time,
-                            })
-                        }
-                        ref_finance_state::Pool::RatedSwapPool(pool) => {
-                            RefPool::RatedSwapPool(RefRatedSwapPool {
-                                token_account_ids: pool
-                                    .token_account_ids
-                                    .into_iter()
-                                    .map(|account_id| account_id.parse().unwrap())
-                                    .collect(),
-                                token_decimals: pool.token_decimals,
-                                c_amounts: pool.c_amounts,
-                                volumes: pool
-                                    .volumes
-                                    .into_iter()
-                                    .map(|volume| RefSwapVolume {
-                                        input: volume.input,
-                                        output: volume.output,
-                                    })
-                                    .collect(),
-                                total_fee: pool.total_fee,
-                                shares_total_supply: pool.shares_total_supply,
-                                init_amp_factor: pool.init_amp_factor,
-                                target_amp_factor: pool.target_amp_factor,
-                                init_amp_time: pool.init_amp_time,
-                                stop_amp_time: pool.stop_amp_time,
-                            })
-                        }
-                    })
-                }
-                PoolType::Aidols(pool) => {
-                    intear_events::events::trade::trade_pool_change::PoolType::Aidols(pool)
-                }
-            },
-            block_height: event.block_height,
-            block_timestamp_nanosec: event.block_timestamp_nanosec,
-            receipt_id: event.receipt_id,
-        });
+        if let PoolType::Ref(ref_finance_state::Pool::Unknown { discriminant, .. }) = &event.pool {
+            self.unknown_pool_change_stream
+                .add_event(UnknownPoolChangeEvent {
+                    pool_id: event.pool_id,
+                    pool_type: "unknown".to_owned(),
+                    discriminant: *discriminant,
+                    block_height: event.block_height,
+                    block_timestamp_nanosec: event.block_timestamp_nanosec,
+                    receipt_id: event.receipt_id,
+                });
+            return;
+        }
+        if let Some(event) = trade_pool_change_event(event) {
+            self.pool_change_stream.add_event(event);
+        }
     }
 
     async fn on_memecooking_deposit(&mut self, context: TradeContext, deposit: DepositEvent) {
@@ -225,6 +576,56 @@ impl TradeEventHandler for PushToRedisStream {
         });
     }
 
+    async fn on_commission_collected(
+        &mut self,
+        _context: TradeContext,
+        _pool_id: PoolId,
+        commission: Balance,
+        _total_commission: Balance,
+    ) {
+        // Aidols commissions are always charged in wNEAR
+        *self
+            .pending_protocol_fees
+            .entry(("aidols", self.wrap_near_contract_id().parse().unwrap()))
+            .or_insert(0) += commission;
+    }
+
+    async fn on_ref_deposit(
+        &mut self,
+        trader: AccountId,
+        token: AccountId,
+        amount: Balance,
+        context: TradeContext,
+    ) {
+        self.ref_deposit_stream.add_event(RefDepositEvent {
+            trader,
+            token,
+            amount,
+            block_height: context.block_height,
+            block_timestamp_nanosec: context.block_timestamp_nanosec,
+            transaction_id: context.transaction_id,
+            receipt_id: context.receipt_id,
+        });
+    }
+
+    async fn on_ref_withdraw(
+        &mut self,
+        trader: AccountId,
+        token: AccountId,
+        amount: Balance,
+        context: TradeContext,
+    ) {
+        self.ref_withdrawal_stream.add_event(RefDepositEvent {
+            trader,
+            token,
+            amount,
+            block_height: context.block_height,
+            block_timestamp_nanosec: context.block_timestamp_nanosec,
+            transaction_id: context.transaction_id,
+            receipt_id: context.receipt_id,
+        });
+    }
+
     async fn flush_events(&mut self, block_height: BlockHeight) {
         self.pool_stream
             .flush_events(block_height, self.max_stream_size)
@@ -250,5 +651,127 @@ impl TradeEventHandler for PushToRedisStream {
             .flush_events(block_height, self.max_stream_size)
             .await
             .expect("Failed to flush liquidity pool stream");
+        self.referral_stream
+            .flush_events(block_height, self.max_stream_size)
+            .await
+            .expect("Failed to flush referral stream");
+        self.unknown_pool_change_stream
+            .flush_events(block_height, self.max_stream_size)
+            .await
+            .expect("Failed to flush unknown pool change stream");
+        self.ref_deposit_stream
+            .flush_events(block_height, self.max_stream_size)
+            .await
+            .expect("Failed to flush Ref deposit stream");
+        self.ref_withdrawal_stream
+            .flush_events(block_height, self.max_stream_size)
+            .await
+            .expect("Failed to flush Ref withdrawal stream");
+        self.pool_swap_usd_stream
+            .flush_events(block_height, self.max_stream_size)
+            .await
+            .expect("Failed to flush pool swap USD stream");
+        self.flush_volume_leaderboard().await;
+        self.flush_protocol_fees().await;
+        self.flush_candles().await;
     }
 }
+
+/// Converts a pool change to the published event type, `None` if the pool
+/// type can't be represented in it
+pub(crate) fn trade_pool_change_event(event: PoolChangeEvent) -> Option<TradePoolChangeEvent> {
+    Some(TradePoolChangeEvent {
+        pool_id: event.pool_id.clone(),
+        pool: match event.pool {
+            PoolType::Ref(pool) => {
+                intear_events::events::trade::trade_pool_change::PoolType::Ref(match pool {
+                    ref_finance_state::Pool::SimplePool(pool) => {
+                        RefPool::SimplePool(RefSimplePool {
+                            token_account_ids: pool
+                                .token_account_ids
+                                .into_iter()
+                                .map(|account_id| account_id.parse().unwrap())
+                                .collect(),
+                            amounts: pool.amounts,
+                            volumes: pool
+                                .volumes
+                                .into_iter()
+                                .map(|volume| RefSwapVolume {
+                                    input: volume.input,
+                                    output: volume.output,
+                                })
+                                .collect(),
+                            total_fee: pool.total_fee,
+                            exchange_fee: pool.exchange_fee,
+                            referral_fee: pool.referral_fee,
+                            shares_total_supply: pool.shares_total_supply,
+                        })
+                    }
+                    ref_finance_state::Pool::StableSwapPool(pool) => {
+                        RefPool::StableSwapPool(RefStableSwapPool {
+                            token_account_ids: pool
+                                .token_account_ids
+                                .into_iter()
+                                .map(|account_id| account_id.parse().unwrap())
+                                .collect(),
+                            token_decimals: pool.token_decimals,
+                            c_amounts: pool.c_amounts,
+                            volumes: pool
+                                .volumes
+                                .into_iter()
+                                .map(|volume| RefSwapVolume {
+                                    input: volume.input,
+                                    output: volume.output,
+                                })
+                                .collect(),
+                            total_fee: pool.total_fee,
+                            shares_total_supply: pool.shares_total_supply,
+                            init_amp_factor: pool.init_amp_factor,
+                            target_amp_factor: pool.target_amp_factor,
+                            init_amp_time: pool.init_amp_time,
+                            stop_amp_time: pool.stop_amp_time,
+                        })
+                    }
+                    ref_finance_state::Pool::RatedSwapPool(pool) => {
+                        RefPool::RatedSwapPool(RefRatedSwapPool {
+                            token_account_ids: pool
+                                .token_account_ids
+                                .into_iter()
+                                .map(|account_id| account_id.parse().unwrap())
+                                .collect(),
+                            token_decimals: pool.token_decimals,
+                            c_amounts: pool.c_amounts,
+                            volumes: pool
+                                .volumes
+                                .into_iter()
+                                .map(|volume| RefSwapVolume {
+                                    input: volume.input,
+                                    output: volume.output,
+                                })
+                                .collect(),
+                            total_fee: pool.total_fee,
+                            shares_total_supply: pool.shares_total_supply,
+                            init_amp_factor: pool.init_amp_factor,
+                            target_amp_factor: pool.target_amp_factor,
+                            init_amp_time: pool.init_amp_time,
+                            stop_amp_time: pool.stop_amp_time,
+                        })
+                    }
+                    ref_finance_state::Pool::Unknown { discriminant, .. } => {
+                        log::warn!(
+                            "Can't push pool {} with unknown Ref pool type {discriminant}",
+                            event.pool_id
+                        );
+                        return None;
+                    }
+                })
+            }
+            PoolType::Aidols(pool) => {
+                intear_events::events::trade::trade_pool_change::PoolType::Aidols(pool)
+            }
+        },
+        block_height: event.block_height,
+        block_timestamp_nanosec: event.block_timestamp_nanosec,
+        receipt_id: event.receipt_id,
+    })
+}
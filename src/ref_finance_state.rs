@@ -1,18 +1,291 @@
 use borsh::{BorshDeserialize, BorshSerialize};
-use inindexer::near_indexer_primitives::types::Balance;
+use inindexer::near_indexer_primitives::types::{AccountId, Balance};
+
+use crate::aidols_state::mul_div;
 
 type SdkTimestamp = u64;
 type SdkAccountId = String;
 
-#[allow(clippy::enum_variant_names)]
-#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+/// Pool fees are in units of 1 / FEE_DIVISOR of the amount in
+pub const FEE_DIVISOR: u32 = 10_000;
+
+/// Number of decimals that stable pool `c_amounts` are scaled to
+pub const STABLE_POOL_TARGET_DECIMALS: u8 = 18;
+
+/// New pool types are added when Ref deploys them, see [`crate::PoolType`]
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
 pub enum Pool {
     SimplePool(SimplePool),
     StableSwapPool(StableSwapPool),
     RatedSwapPool(RatedSwapPool),
+    /// A pool type added to the contract after this indexer was written.
+    /// `raw_bytes` is everything after the discriminant.
+    Unknown {
+        discriminant: u8,
+        raw_bytes: Vec<u8>,
+    },
+}
+
+// Borsh derive can't fall back to a catch-all variant, so the enum layout is
+// written out by hand.
+impl BorshDeserialize for Pool {
+    fn deserialize_reader<R: std::io::Read>(reader: &mut R) -> std::io::Result<Self> {
+        let discriminant = u8::deserialize_reader(reader)?;
+        Ok(match discriminant {
+            0 => Pool::SimplePool(SimplePool::deserialize_reader(reader)?),
+            1 => Pool::StableSwapPool(StableSwapPool::deserialize_reader(reader)?),
+            2 => Pool::RatedSwapPool(RatedSwapPool::deserialize_reader(reader)?),
+            discriminant => {
+                let mut raw_bytes = Vec::new();
+                reader.read_to_end(&mut raw_bytes)?;
+                Pool::Unknown {
+                    discriminant,
+                    raw_bytes,
+                }
+            }
+        })
+    }
+}
+
+impl BorshSerialize for Pool {
+    fn serialize<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        match self {
+            Pool::SimplePool(pool) => {
+                0u8.serialize(writer)?;
+                pool.serialize(writer)
+            }
+            Pool::StableSwapPool(pool) => {
+                1u8.serialize(writer)?;
+                pool.serialize(writer)
+            }
+            Pool::RatedSwapPool(pool) => {
+                2u8.serialize(writer)?;
+                pool.serialize(writer)
+            }
+            Pool::Unknown {
+                discriminant,
+                raw_bytes,
+            } => {
+                discriminant.serialize(writer)?;
+                writer.write_all(raw_bytes)
+            }
+        }
+    }
+}
+
+impl Pool {
+    /// Amplification coefficient schedule of stable and rated pools
+    pub fn amp_ramp(&self) -> Option<AmpRamp> {
+        match self {
+            Pool::StableSwapPool(StableSwapPool {
+                init_amp_factor,
+                target_amp_factor,
+                init_amp_time,
+                stop_amp_time,
+                ..
+            })
+            | Pool::RatedSwapPool(RatedSwapPool {
+                init_amp_factor,
+                target_amp_factor,
+                init_amp_time,
+                stop_amp_time,
+                ..
+            }) => Some(AmpRamp {
+                init_amp_factor: *init_amp_factor,
+                target_amp_factor: *target_amp_factor,
+                init_amp_time: *init_amp_time,
+                stop_amp_time: *stop_amp_time,
+            }),
+            Pool::SimplePool(_) | Pool::Unknown { .. } => None,
+        }
+    }
+
+    /// Raw token amounts in the pool. `None` for stable and rated pools, their
+    /// `c_amounts` are scaled to a common number of decimals.
+    pub fn reserves(&self) -> Option<Vec<(&str, Balance)>> {
+        match self {
+            Pool::SimplePool(pool) => Some(
+                pool.token_account_ids
+                    .iter()
+                    .map(String::as_str)
+                    .zip(pool.amounts.iter().copied())
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Fees of the pool, `None` for unknown pool types. Stable and rated pools only
+    /// have a total fee.
+    pub fn fees(&self) -> Option<PoolFees> {
+        match self {
+            Pool::SimplePool(pool) => Some(PoolFees {
+                total_fee: pool.total_fee,
+                exchange_fee: pool.exchange_fee,
+                referral_fee: pool.referral_fee,
+            }),
+            Pool::StableSwapPool(StableSwapPool { total_fee, .. })
+            | Pool::RatedSwapPool(RatedSwapPool { total_fee, .. }) => Some(PoolFees {
+                total_fee: *total_fee,
+                exchange_fee: 0,
+                referral_fee: 0,
+            }),
+            Pool::Unknown { .. } => None,
+        }
+    }
+
+    /// Cumulative swap volumes of each token, `None` for unknown pool types
+    pub fn volumes(&self) -> Option<&[SwapVolume]> {
+        match self {
+            Pool::SimplePool(pool) => Some(&pool.volumes),
+            Pool::StableSwapPool(pool) => Some(&pool.volumes),
+            Pool::RatedSwapPool(pool) => Some(&pool.volumes),
+            Pool::Unknown { .. } => None,
+        }
+    }
+
+    /// Checks that the per-token vectors of the pool have the same length, which
+    /// the contract always maintains.
+    pub fn validate(&self) -> Result<(), String> {
+        let lengths = match self {
+            Pool::SimplePool(pool) => vec![
+                ("token_account_ids", pool.token_account_ids.len()),
+                ("amounts", pool.amounts.len()),
+                ("volumes", pool.volumes.len()),
+            ],
+            Pool::StableSwapPool(StableSwapPool {
+                token_account_ids,
+                token_decimals,
+                c_amounts,
+                volumes,
+                ..
+            })
+            | Pool::RatedSwapPool(RatedSwapPool {
+                token_account_ids,
+                token_decimals,
+                c_amounts,
+                volumes,
+                ..
+            }) => vec![
+                ("token_account_ids", token_account_ids.len()),
+                ("token_decimals", token_decimals.len()),
+                ("c_amounts", c_amounts.len()),
+                ("volumes", volumes.len()),
+            ],
+            Pool::Unknown { .. } => return Ok(()),
+        };
+        if lengths.iter().all(|(_, len)| *len == lengths[0].1) {
+            Ok(())
+        } else {
+            Err(format!("token vectors have different lengths: {lengths:?}"))
+        }
+    }
+
+    /// Sanity checks for values that a correctly deserialized pool shouldn't have.
+    /// A non-empty result usually means the contract was upgraded and the Borsh
+    /// layout no longer matches.
+    pub fn integrity_issues(&self) -> Vec<String> {
+        let mut issues = Vec::new();
+        match self {
+            Pool::SimplePool(pool) => {
+                for ((token, amount), volume) in pool
+                    .token_account_ids
+                    .iter()
+                    .zip(&pool.amounts)
+                    .zip(&pool.volumes)
+                {
+                    if volume.input < *amount {
+                        issues.push(format!(
+                            "reserve of {token} ({amount}) exceeds its cumulative input volume ({})",
+                            volume.input
+                        ));
+                    }
+                }
+            }
+            Pool::StableSwapPool(StableSwapPool {
+                c_amounts,
+                shares_total_supply,
+                ..
+            })
+            | Pool::RatedSwapPool(RatedSwapPool {
+                c_amounts,
+                shares_total_supply,
+                ..
+            }) => {
+                // Upper bound is shares_total_supply * 2^128, which any u128 amount is
+                // below unless there are no shares at all.
+                if *shares_total_supply == 0 && c_amounts.iter().any(|amount| *amount > 0) {
+                    issues.push(format!(
+                        "c_amounts {c_amounts:?} are non-zero while shares_total_supply is 0"
+                    ));
+                }
+            }
+            Pool::Unknown { .. } => {}
+        }
+        issues
+    }
+
+    /// Amount of each token that `shares` of a stable or rated pool are worth, a
+    /// proportional part of `c_amounts`. Empty for other pool types.
+    pub fn share_value(&self, shares: Balance) -> Vec<(AccountId, Balance)> {
+        let (token_account_ids, token_decimals, c_amounts, shares_total_supply) = match self {
+            Pool::StableSwapPool(StableSwapPool {
+                token_account_ids,
+                token_decimals,
+                c_amounts,
+                shares_total_supply,
+                ..
+            })
+            | Pool::RatedSwapPool(RatedSwapPool {
+                token_account_ids,
+                token_decimals,
+                c_amounts,
+                shares_total_supply,
+                ..
+            }) => (
+                token_account_ids,
+                token_decimals,
+                c_amounts,
+                *shares_total_supply,
+            ),
+            Pool::SimplePool(_) | Pool::Unknown { .. } => return Vec::new(),
+        };
+        token_account_ids
+            .iter()
+            .zip(token_decimals)
+            .zip(c_amounts)
+            .filter_map(|((token, decimals), c_amount)| {
+                let token = token.parse::<AccountId>().ok()?;
+                let scale =
+                    10u128.pow(STABLE_POOL_TARGET_DECIMALS.saturating_sub(*decimals) as u32);
+                let amount = mul_div(*c_amount, shares, shares_total_supply)? / scale;
+                (amount > 0).then_some((token, amount))
+            })
+            .collect()
+    }
+
+    /// Lowercases token account IDs, old pools can have tokens that were added
+    /// before NEAR required account IDs to be lowercase. Returns the raw ID of the
+    /// first token that still isn't a valid account ID.
+    pub fn canonicalize_token_account_ids(&mut self) -> Result<(), String> {
+        let token_account_ids = match self {
+            Pool::SimplePool(pool) => &mut pool.token_account_ids,
+            Pool::StableSwapPool(pool) => &mut pool.token_account_ids,
+            Pool::RatedSwapPool(pool) => &mut pool.token_account_ids,
+            Pool::Unknown { .. } => return Ok(()),
+        };
+        for account_id in token_account_ids {
+            match crate::canonicalize_account_id(account_id) {
+                Some(canonical) => *account_id = canonical.to_string(),
+                None => return Err(account_id.clone()),
+            }
+        }
+        Ok(())
+    }
 }
 
-#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
 pub struct SimplePool {
     /// List of tokens in the pool.
     pub token_account_ids: Vec<SdkAccountId>,
@@ -32,13 +305,25 @@ pub struct SimplePool {
     pub shares_total_supply: Balance,
 }
 
-#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+impl SimplePool {
+    /// Token amounts divided by `10^decimals[i]`, for display. `decimals` is in the
+    /// same order as `token_account_ids`.
+    pub fn amounts_human(&self, decimals: Vec<u8>) -> Vec<f64> {
+        self.amounts
+            .iter()
+            .zip(decimals)
+            .map(|(amount, decimals)| *amount as f64 / 10f64.powi(decimals as i32))
+            .collect()
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
 pub struct SwapVolume {
     pub input: u128,
     pub output: u128,
 }
 
-#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
 pub struct StableSwapPool {
     /// List of tokens in the pool.
     pub token_account_ids: Vec<SdkAccountId>,
@@ -64,7 +349,31 @@ pub struct StableSwapPool {
     pub stop_amp_time: SdkTimestamp,
 }
 
-#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+impl StableSwapPool {
+    /// `c_amounts` converted back to raw token amounts using `token_decimals`
+    pub fn actual_amounts(&self) -> Vec<u128> {
+        self.c_amounts
+            .iter()
+            .zip(&self.token_decimals)
+            .map(|(amount, decimals)| {
+                amount / 10u128.pow(STABLE_POOL_TARGET_DECIMALS.saturating_sub(*decimals) as u32)
+            })
+            .collect()
+    }
+
+    /// Amplification coefficient at `timestamp` (nanoseconds), see [`AmpRamp::amp_factor_at`]
+    pub fn current_amp_factor(&self, timestamp: u64) -> u128 {
+        AmpRamp {
+            init_amp_factor: self.init_amp_factor,
+            target_amp_factor: self.target_amp_factor,
+            init_amp_time: self.init_amp_time,
+            stop_amp_time: self.stop_amp_time,
+        }
+        .amp_factor_at(timestamp)
+    }
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
 pub struct RatedSwapPool {
     /// List of tokens in the pool.
     pub token_account_ids: Vec<SdkAccountId>,
@@ -89,3 +398,58 @@ pub struct RatedSwapPool {
     /// Stop ramp up amplification time.
     pub stop_amp_time: SdkTimestamp,
 }
+
+impl RatedSwapPool {
+    /// Amplification coefficient at `timestamp` (nanoseconds), see [`AmpRamp::amp_factor_at`]
+    pub fn current_amp_factor(&self, timestamp: u64) -> u128 {
+        AmpRamp {
+            init_amp_factor: self.init_amp_factor,
+            target_amp_factor: self.target_amp_factor,
+            init_amp_time: self.init_amp_time,
+            stop_amp_time: self.stop_amp_time,
+        }
+        .amp_factor_at(timestamp)
+    }
+}
+
+/// Fees of a pool, divided by [`FEE_DIVISOR`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PoolFees {
+    pub total_fee: u32,
+    pub exchange_fee: u32,
+    pub referral_fee: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AmpRamp {
+    /// Amplification coefficient at `init_amp_time`.
+    pub init_amp_factor: u128,
+    /// Amplification coefficient reached at `stop_amp_time`.
+    pub target_amp_factor: u128,
+    pub init_amp_time: SdkTimestamp,
+    pub stop_amp_time: SdkTimestamp,
+}
+
+impl AmpRamp {
+    /// Linearly interpolates the amplification coefficient between `init_amp_time`
+    /// and `stop_amp_time` the same way the contract does, rounding towards
+    /// `init_amp_factor`. Before the ramp it's `init_amp_factor`, after it (or if
+    /// there's no ramp) it's `target_amp_factor`.
+    pub fn amp_factor_at(&self, timestamp: SdkTimestamp) -> u128 {
+        if timestamp >= self.stop_amp_time {
+            return self.target_amp_factor;
+        }
+        if timestamp <= self.init_amp_time {
+            return self.init_amp_factor;
+        }
+        let time_range = (self.stop_amp_time - self.init_amp_time) as u128;
+        let time_delta = (timestamp - self.init_amp_time) as u128;
+        if self.target_amp_factor >= self.init_amp_factor {
+            let amp_range = self.target_amp_factor - self.init_amp_factor;
+            self.init_amp_factor + amp_range * time_delta / time_range
+        } else {
+            let amp_range = self.init_amp_factor - self.target_amp_factor;
+            self.init_amp_factor - amp_range * time_delta / time_range
+        }
+    }
+}
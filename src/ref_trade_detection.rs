@@ -1,10 +1,10 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use inindexer::near_utils::dec_format_vec;
 use inindexer::{
     near_indexer_primitives::{
         types::{AccountId, Balance},
-        views::{ActionView, ReceiptEnumView},
+        views::{ActionView, ExecutionStatusView, ReceiptEnumView},
         StreamerMessage,
     },
     near_utils::dec_format,
@@ -12,37 +12,85 @@ use inindexer::{
 };
 use serde::Deserialize;
 
+use crate::ref_finance_state::PoolFees;
+use crate::usd_oracle::UsdOracle;
 use crate::{
-    find_parent_receipt, BalanceChangeSwap, PoolId, RawPoolSwap, TradeContext, TradeEventHandler,
+    canonicalize_account_id, failed_child_receipts, find_parent_receipt, is_valid_trader,
+    BalanceChangeSwap, FeeBreakdown, PoolId, RawPoolSwap, SwapGasStats, SwapMethod, TradeContext,
+    TradeEventHandler, NATIVE_NEAR_TOKEN_ID,
 };
 
 pub const TESTNET_REF_CONTRACT_ID: &str = "ref-finance-101.testnet";
 pub const REF_CONTRACT_ID: &str = "v2.ref-finance.near";
+pub const AURORA_CONTRACT_ID: &str = "aurora";
+pub const REF_FARM_CONTRACT_ID: &str = "v2.ref-farming.near";
+pub const WRAP_NEAR_CONTRACT_ID: &str = "wrap.near";
+pub const TESTNET_WRAP_NEAR_CONTRACT_ID: &str = "wrap.testnet";
+/// Message of swaps in a pool that was paused by the Ref admins
+pub const POOL_PAUSED_MESSAGE: &str = "Pool is paused";
 
+/// Returns the pool swaps that were sent to the handler
+#[allow(clippy::too_many_arguments)]
 pub async fn detect(
     receipt: &TransactionReceipt,
     transaction: &IncompleteTransaction,
     block: &StreamerMessage,
     handler: &mut impl TradeEventHandler,
     is_testnet: bool,
-) {
+    aggregator_contracts: &HashSet<AccountId>,
+    relayer_depths: &HashMap<AccountId, u8>,
+    pool_fees: Option<&HashMap<PoolId, PoolFees>>,
+    usd_oracle: Option<&UsdOracle>,
+) -> Vec<RawPoolSwap> {
     let ref_contract_id = if is_testnet {
         TESTNET_REF_CONTRACT_ID
     } else {
         REF_CONTRACT_ID
     };
     if receipt.is_successful(false) && receipt.receipt.receipt.receiver_id == ref_contract_id {
+        // Ref doesn't fail the swap receipt if e.g. sending the output tokens fails, the
+        // swap still happened and the tokens stay in the trader's Ref deposit
+        for child in failed_child_receipts(transaction, receipt) {
+            log::warn!(
+                "Ref receipt {} succeeded but its child receipt {} failed",
+                receipt.receipt.receipt.receipt_id,
+                child.receipt.receipt.receipt_id
+            );
+        }
         let mut raw_pool_swaps = vec![];
         let mut balance_changes = HashMap::new();
         let mut trader = receipt.receipt.receipt.predecessor_id.clone();
+        // Contracts that accept tokens via ft_transfer_call and swap them on Ref on
+        // behalf of the sender: token -> intermediary.ft_on_transfer -> ref.swap
+        if let Some(sender_id) = find_parent_receipt(transaction, receipt)
+            .and_then(|parent| ft_on_transfer_sender(parent, &trader))
+        {
+            trader = sender_id;
+        }
         let mut swap_action_pools = vec![];
+        let mut output_to = None;
+        let mut is_hot_zap = false;
+        let mut referral = None;
+        let mut swap_method = None;
         let mut swap_logs_in_receipt = Vec::new();
         if let ReceiptEnumView::Action { actions, .. } = &receipt.receipt.receipt.receipt {
             for action in actions {
                 if let ActionView::FunctionCall {
-                    method_name, args, ..
+                    method_name,
+                    args,
+                    gas,
+                    deposit,
                 } = action
                 {
+                    // View calls like get_return aren't receipts on chain, but a provider
+                    // could include simulated ones. They can't change any state.
+                    if *gas == 0 && *deposit == 0 {
+                        log::debug!(
+                            "Skipping view call {method_name} in receipt {}",
+                            receipt.receipt.receipt.receipt_id
+                        );
+                        return Vec::new();
+                    }
                     if method_name == "ft_on_transfer" {
                         if let Some(caller_receipt) = transaction
                             .receipts
@@ -62,59 +110,60 @@ pub async fn detect(
                             if let Ok(call) =
                                 serde_json::from_str::<FtTransferCallArgsExecute>(&call.msg)
                             {
+                                if trader == AURORA_CONTRACT_ID {
+                                    if let Some(aurora_trader) =
+                                        call.aurora_sender.as_deref().and_then(aurora_account_id)
+                                    {
+                                        trader = aurora_trader;
+                                    }
+                                }
                                 swap_action_pools
-                                    .extend(call.actions.into_iter().map(|a| a.pool_id))
+                                    .extend(call.actions.into_iter().map(|a| a.pool_id));
+                                swap_method = Some(SwapMethod::Instant);
                             } else if let Ok(call) =
                                 serde_json::from_str::<FtTransferCallArgsHotZap>(&call.msg)
                             {
                                 swap_action_pools
                                     .extend(call.hot_zap_actions.into_iter().map(|a| a.pool_id));
+                                output_to = call.output_to;
+                                is_hot_zap = true;
+                                swap_method = Some(SwapMethod::Instant);
                             }
                         }
                     } else if method_name == "swap" {
                         if let Ok(call) = serde_json::from_slice::<MethodSwap>(args) {
                             swap_action_pools.extend(call.actions.into_iter().map(|a| a.pool_id));
+                            swap_method = Some(SwapMethod::Deposited);
                         }
                     } else if method_name == "swap_by_output" {
                         if let Ok(call) = serde_json::from_slice::<MethodSwapByOutput>(args) {
                             swap_action_pools.extend(call.actions.into_iter().map(|a| a.pool_id));
+                            swap_method = Some(SwapMethod::Deposited);
                         }
                     } else if method_name == "execute_actions" {
                         if let Ok(call) = serde_json::from_slice::<MethodExecuteActions>(args) {
                             swap_action_pools.extend(call.actions.into_iter().map(|a| a.pool_id));
+                            referral = call.referral_id;
+                            swap_method = Some(SwapMethod::Deposited);
                         }
-                    } else if method_name == "add_liquidity" {
+                    } else if method_name == "add_liquidity"
+                        || method_name == "add_stable_liquidity"
+                        || method_name == "add_liquidity_by_tokens"
+                    {
+                        // add_stable_liquidity takes non-proportional amounts and an extra
+                        // min_shares arg, the amounts are still taken from the log.
+                        // add_liquidity_by_tokens (pool_id, amounts, min_shares) isn't on
+                        // the contract yet, it's matched in case it's added with the same log.
                         if let Ok(call) =
                             serde_json::from_slice::<FtTransferCallArgsAddLiquidity>(args)
                         {
                             let pool_id = call.pool_id;
                             for log in &receipt.receipt.execution_outcome.outcome.logs {
-                                // format: "Liquidity added ["999999999999999915648607 wrap.near", "15869989324782287999975226 intel.tkn.near"], minted 514844781930897970949 shares"
-                                let Some(log) = log.strip_prefix("Liquidity added [\"") else {
-                                    return;
+                                let Some(tokens) = parse_liquidity_added_log(log) else {
+                                    return Vec::new();
                                 };
-                                let Some(log) = log.strip_suffix(" shares") else {
-                                    return;
-                                };
-                                let Some((amounts, shares)) = log.split_once("\"], minted ") else {
-                                    return;
-                                };
-                                let amounts = amounts.split("\", \"").collect::<Vec<_>>();
-                                let Ok(_shares) = shares.parse::<Balance>() else {
-                                    return;
-                                };
-                                let mut tokens = HashMap::new();
-                                for amount in amounts {
-                                    let Some((amount, token)) = amount.split_once(' ') else {
-                                        return;
-                                    };
-                                    let Ok(amount) = amount.parse::<Balance>() else {
-                                        return;
-                                    };
-                                    let Ok(token) = token.parse::<AccountId>() else {
-                                        return;
-                                    };
-                                    tokens.insert(token, amount as i128);
+                                if !is_valid_trader(&trader, receipt) {
+                                    return Vec::new();
                                 }
                                 handler
                                     .on_liquidity_pool(
@@ -142,31 +191,16 @@ pub async fn detect(
                         if let Ok(call) = serde_json::from_slice::<RemoveLiquidity>(args) {
                             let pool_id = call.pool_id;
                             for log in &receipt.receipt.execution_outcome.outcome.logs {
-                                // format: "514844781930897970949 shares of liquidity removed: receive back ["1000312838374558764552331 wrap.near", "15865198314126424586378752 intel.tkn.near"]"
-                                let Some((shares, tokens)) = log
-                                    .split_once(" shares of liquidity removed: receive back [\"")
+                                let Some((_shares, amounts)) = parse_liquidity_removed_log(log)
                                 else {
-                                    return;
-                                };
-                                let Ok(_shares) = shares.parse::<Balance>() else {
-                                    return;
+                                    return Vec::new();
                                 };
-                                let Some(tokens) = tokens.strip_suffix("\"]") else {
-                                    return;
-                                };
-                                let tokens = tokens.split("\", \"").collect::<Vec<_>>();
-                                let mut amounts = HashMap::new();
-                                for token in tokens {
-                                    let Some((amount, token)) = token.split_once(' ') else {
-                                        return;
-                                    };
-                                    let Ok(amount) = amount.parse::<Balance>() else {
-                                        return;
-                                    };
-                                    let Ok(token) = token.parse::<AccountId>() else {
-                                        return;
-                                    };
-                                    amounts.insert(token, -(amount as i128));
+                                let amounts = amounts
+                                    .into_iter()
+                                    .map(|(token, amount)| (token, -(amount as i128)))
+                                    .collect();
+                                if !is_valid_trader(&trader, receipt) {
+                                    return Vec::new();
                                 }
                                 handler
                                     .on_liquidity_pool(
@@ -190,6 +224,93 @@ pub async fn detect(
                                     .await;
                             }
                         }
+                    } else if method_name == "storage_withdraw" {
+                        let account_id = receipt.receipt.receipt.predecessor_id.clone();
+                        let amount = serde_json::from_slice::<StorageWithdraw>(args)
+                            .ok()
+                            .and_then(|call| call.amount)
+                            .or_else(|| withdrawn_near(transaction, receipt, &account_id));
+                        let Some(amount) = amount else {
+                            log::warn!(
+                                "Couldn't find the amount withdrawn in storage_withdraw receipt {}",
+                                receipt.receipt.receipt.receipt_id
+                            );
+                            continue;
+                        };
+                        if !is_valid_trader(&account_id, receipt) {
+                            continue;
+                        }
+                        handler
+                            .on_storage_withdrawn(
+                                TradeContext {
+                                    trader: account_id.clone(),
+                                    block_height: block.block.header.height,
+                                    block_timestamp_nanosec: block.block.header.timestamp_nanosec
+                                        as u128,
+                                    transaction_id: transaction.transaction.transaction.hash,
+                                    receipt_id: receipt.receipt.receipt.receipt_id,
+                                },
+                                account_id,
+                                amount,
+                            )
+                            .await;
+                    } else if method_name == "storage_unregister" {
+                        let account_id = receipt.receipt.receipt.predecessor_id.clone();
+                        // Returns false if the account wasn't registered
+                        if !matches!(
+                            &receipt.receipt.execution_outcome.outcome.status,
+                            ExecutionStatusView::SuccessValue(value) if value.as_slice() == b"true"
+                        ) {
+                            continue;
+                        }
+                        if !is_valid_trader(&account_id, receipt) {
+                            continue;
+                        }
+                        handler
+                            .on_account_unregistered(
+                                TradeContext {
+                                    trader: account_id.clone(),
+                                    block_height: block.block.header.height,
+                                    block_timestamp_nanosec: block.block.header.timestamp_nanosec
+                                        as u128,
+                                    transaction_id: transaction.transaction.transaction.hash,
+                                    receipt_id: receipt.receipt.receipt.receipt_id,
+                                },
+                                account_id,
+                            )
+                            .await;
+                    } else if method_name == "mft_transfer_call" {
+                        if let Ok(call) = serde_json::from_slice::<MftTransferCall>(args) {
+                            // LP shares of pool N have token id ":N"
+                            let Some(Ok(pool_id)) =
+                                call.token_id.strip_prefix(':').map(str::parse::<u64>)
+                            else {
+                                continue;
+                            };
+                            let sender = receipt.receipt.receipt.predecessor_id.clone();
+                            if !is_valid_trader(&sender, receipt) {
+                                continue;
+                            }
+                            handler
+                                .on_lp_share_transfer(
+                                    TradeContext {
+                                        trader: sender.clone(),
+                                        block_height: block.block.header.height,
+                                        block_timestamp_nanosec: block
+                                            .block
+                                            .header
+                                            .timestamp_nanosec
+                                            as u128,
+                                        transaction_id: transaction.transaction.transaction.hash,
+                                        receipt_id: receipt.receipt.receipt.receipt_id,
+                                    },
+                                    create_ref_pool_id(pool_id),
+                                    sender,
+                                    call.receiver_id,
+                                    call.amount,
+                                )
+                                .await;
+                        }
                     }
                     // There could be some edge cases with both "swap" and "ft_transfer_call" as
                     // separate actions in one transaction (if it's possible to have 2 function
@@ -200,26 +321,36 @@ pub async fn detect(
             }
         }
 
-        if trader == "ref.hot.tg" {
-            if let Some(receipt) = find_parent_receipt(transaction, receipt) {
-                if let Some(receipt) = find_parent_receipt(transaction, receipt) {
-                    trader = receipt.receipt.receipt.predecessor_id.clone();
-                } else {
+        if let Some(depth) = relayer_depths.get(&trader).copied() {
+            let mut user_receipt = receipt;
+            for level in 1..=depth {
+                let Some(parent) = find_parent_receipt(transaction, user_receipt) else {
                     log::warn!(
-                        "Could not find the parent receipt of the parent receipt of the ref.hot.tg trade {:?}",
+                        "Could not find the parent receipt {level} levels up of the {trader} trade {:?}, expected {depth} levels",
                         transaction.transaction.transaction.hash
                     );
-                    return;
-                }
+                    return Vec::new();
+                };
+                user_receipt = parent;
+            }
+            trader = user_receipt.receipt.receipt.predecessor_id.clone();
+        }
+
+        if aggregator_contracts.contains(&trader) {
+            if let Some(caller) = find_aggregator_caller(transaction, receipt, &trader) {
+                trader = caller;
             } else {
                 log::warn!(
-                    "Could not find the parent receipt of the ref.hot.tg trade {:?}",
+                    "Could not find the caller of aggregator {trader} in trade {:?}",
                     transaction.transaction.transaction.hash
                 );
-                return;
+                return Vec::new();
             }
         }
 
+        // Swap_by_output logs contain the amount_in the pool actually took (computed
+        // from amount_out), not the max_amount_in from the call, so the logs can be
+        // used as-is. The output transfer is a separate child receipt and isn't needed.
         for log in &receipt.receipt.execution_outcome.outcome.logs {
             if let (Some(log), _) | (_, Some(log)) = (
                 log.strip_prefix("Swapped "),
@@ -229,9 +360,9 @@ pub async fn detect(
                     let token_out = token_out.split(',').next().unwrap();
                     let (amount_in, token_in) = token_in.split_once(' ').unwrap();
                     let (amount_out, token_out) = token_out.split_once(' ').unwrap();
-                    if let (Ok(token_in), Ok(token_out), Ok(amount_in), Ok(amount_out)) = (
-                        token_in.parse::<AccountId>(),
-                        token_out.parse::<AccountId>(),
+                    if let (Some(token_in), Some(token_out), Ok(amount_in), Ok(amount_out)) = (
+                        canonicalize_account_id(token_in),
+                        canonicalize_account_id(token_out),
                         amount_in.parse::<Balance>(),
                         amount_out.parse::<Balance>(),
                     ) {
@@ -252,6 +383,11 @@ pub async fn detect(
                             token_out,
                             amount_in,
                             amount_out,
+                            referral: None,
+                            referral_commission: None,
+                            fee_breakdown: None,
+                            amount_in_usd: None,
+                            amount_out_usd: None,
                         });
                     }
                 }
@@ -264,25 +400,65 @@ pub async fn detect(
                 receipt.receipt.receipt.receipt,
                 transaction.transaction.transaction.hash
             );
-            return;
+            return Vec::new();
         }
 
         raw_pool_swaps.extend(
             swap_logs_in_receipt
                 .into_iter()
                 .enumerate()
-                .map(|(i, swap)| RawPoolSwap {
-                    pool: create_ref_pool_id(swap_action_pools[i]),
-                    token_in: swap.token_in,
-                    token_out: swap.token_out,
-                    amount_in: swap.amount_in,
-                    amount_out: swap.amount_out,
+                .map(|(i, swap)| {
+                    let pool = create_ref_pool_id(swap_action_pools[i]);
+                    let fee_breakdown = pool_fees
+                        .and_then(|pool_fees| pool_fees.get(&pool))
+                        .map(|fees| FeeBreakdown::from_pool_fees(swap.amount_in, fees));
+                    let amount_in_usd = usd_oracle
+                        .and_then(|oracle| oracle.usd_value(&swap.token_in, swap.amount_in));
+                    let amount_out_usd = usd_oracle
+                        .and_then(|oracle| oracle.usd_value(&swap.token_out, swap.amount_out));
+                    RawPoolSwap {
+                        pool,
+                        token_in: swap.token_in,
+                        token_out: swap.token_out,
+                        amount_in: swap.amount_in,
+                        amount_out: swap.amount_out,
+                        referral: referral.clone(),
+                        // Ref doesn't log the referral fee of each swap
+                        referral_commission: None,
+                        fee_breakdown,
+                        amount_in_usd,
+                        amount_out_usd,
+                    }
                 }),
         );
 
+        for (i, j) in swap_chain_breaks(&raw_pool_swaps) {
+            log::warn!(
+                "Swap {j} in transaction {:?} takes {}, which isn't on the route before it (swap {i} outputs {}): {raw_pool_swaps:?}",
+                transaction.transaction.transaction.hash,
+                raw_pool_swaps[j].token_in,
+                raw_pool_swaps[i].token_out,
+            );
+        }
+
         if raw_pool_swaps.is_empty() {
-            return;
+            return Vec::new();
+        }
+        if !is_valid_trader(&trader, receipt) {
+            return Vec::new();
         }
+        let wrap_near_contract_id = if is_testnet {
+            TESTNET_WRAP_NEAR_CONTRACT_ID
+        } else {
+            WRAP_NEAR_CONTRACT_ID
+        };
+        apply_native_near_changes(
+            transaction,
+            receipt,
+            &trader,
+            wrap_near_contract_id,
+            &mut balance_changes,
+        );
 
         let context = TradeContext {
             trader,
@@ -296,16 +472,553 @@ pub async fn detect(
                 .on_raw_pool_swap(context.clone(), raw_pool_swap)
                 .await;
         }
+        if let Some(method) = swap_method {
+            let mut gas_burnt = receipt.receipt.execution_outcome.outcome.gas_burnt;
+            // The token contract's ft_transfer_call is part of the cost of an instant
+            // swap, a swap from the deposit is a single receipt
+            if method == SwapMethod::Instant {
+                if let Some(parent) = find_parent_receipt(transaction, receipt) {
+                    gas_burnt += parent.receipt.execution_outcome.outcome.gas_burnt;
+                }
+            }
+            handler
+                .on_swap_gas_stats(context.clone(), SwapGasStats { method, gas_burnt })
+                .await;
+        }
+        if let Some(output_to) = output_to.filter(|output_to| *output_to != context.trader) {
+            handler
+                .on_output_redirected(context.clone(), output_to)
+                .await;
+        }
+        if is_hot_zap {
+            // Zaps can put the swap output into a pool instead of sending it to the
+            // trader. LP shares aren't NEP-141 tokens, so they can't be a balance
+            // change, but the tokens that went into the pool didn't reach the trader.
+            for log in zap_liquidity_logs(transaction, receipt) {
+                if let Some(tokens) = parse_liquidity_added_log(log) {
+                    for (token, amount) in tokens {
+                        *balance_changes.entry(token).or_insert(0) -= amount;
+                    }
+                }
+            }
+        }
         balance_changes.retain(|_, v| *v != 0);
-        if !balance_changes.is_empty() {
-            let balance_changes = BalanceChangeSwap {
-                balance_changes,
-                pool_swaps: raw_pool_swaps,
-            };
+        let has_balance_changes = !balance_changes.is_empty();
+        let balance_changes = BalanceChangeSwap {
+            balance_changes,
+            pool_swaps: raw_pool_swaps.clone(),
+        };
+        // A flash swap that returns exactly what it took has no balance changes left
+        if balance_changes.is_flash_swap() {
+            handler
+                .on_flash_swap(context.clone(), balance_changes.clone())
+                .await;
+        }
+        if has_balance_changes {
             handler
                 .on_balance_change_swap(context, balance_changes)
                 .await;
         }
+        raw_pool_swaps
+    } else {
+        Vec::new()
+    }
+}
+
+/// Indices of consecutive swaps where the second takes a token that no earlier
+/// swap took or returned. A split route starts a new hop from a token already on
+/// the route, like the input token, so it isn't a break, and neither is an
+/// arbitrage ending in the token it started with.
+pub(crate) fn swap_chain_breaks(swaps: &[RawPoolSwap]) -> Vec<(usize, usize)> {
+    let mut route_tokens = HashSet::new();
+    let mut breaks = Vec::new();
+    for (i, pair) in swaps.windows(2).enumerate() {
+        route_tokens.insert(&pair[0].token_in);
+        route_tokens.insert(&pair[0].token_out);
+        if !route_tokens.contains(&pair[1].token_in) {
+            breaks.push((i, i + 1));
+        }
+    }
+    breaks
+}
+
+/// Pools of a failed Ref swap receipt that logged or panicked with
+/// [`POOL_PAUSED_MESSAGE`]. The message doesn't say which pool is paused, so all
+/// pools of the swap are returned.
+pub fn paused_swap_pools(receipt: &TransactionReceipt, is_testnet: bool) -> Vec<PoolId> {
+    let outcome = &receipt.receipt.execution_outcome.outcome;
+    let is_paused = outcome
+        .logs
+        .iter()
+        .any(|log| log.contains(POOL_PAUSED_MESSAGE))
+        || matches!(
+            &outcome.status,
+            ExecutionStatusView::Failure(err) if err.to_string().contains(POOL_PAUSED_MESSAGE)
+        );
+    if !is_paused {
+        return Vec::new();
+    }
+    failed_swap_pools(receipt, is_testnet)
+}
+
+/// Pools that a failed Ref swap receipt tried to swap in
+pub fn failed_swap_pools(receipt: &TransactionReceipt, is_testnet: bool) -> Vec<PoolId> {
+    let ref_contract_id = if is_testnet {
+        TESTNET_REF_CONTRACT_ID
+    } else {
+        REF_CONTRACT_ID
+    };
+    if receipt.is_successful(false) || receipt.receipt.receipt.receiver_id != ref_contract_id {
+        return Vec::new();
+    }
+    let ReceiptEnumView::Action { actions, .. } = &receipt.receipt.receipt.receipt else {
+        return Vec::new();
+    };
+    let mut pools = Vec::new();
+    for action in actions {
+        let ActionView::FunctionCall {
+            method_name, args, ..
+        } = action
+        else {
+            continue;
+        };
+        let pool_ids = match method_name.as_str() {
+            "swap" => serde_json::from_slice::<MethodSwap>(args)
+                .map(|call| call.actions.into_iter().map(|a| a.pool_id).collect())
+                .unwrap_or_default(),
+            "swap_by_output" => serde_json::from_slice::<MethodSwapByOutput>(args)
+                .map(|call| call.actions.into_iter().map(|a| a.pool_id).collect())
+                .unwrap_or_default(),
+            "execute_actions" => serde_json::from_slice::<MethodExecuteActions>(args)
+                .map(|call| call.actions.into_iter().map(|a| a.pool_id).collect())
+                .unwrap_or_default(),
+            "ft_on_transfer" => serde_json::from_slice::<FtTransferCallArgs>(args)
+                .ok()
+                .and_then(|call| {
+                    serde_json::from_str::<FtTransferCallArgsExecute>(&call.msg)
+                        .map(|call| call.actions.into_iter().map(|a| a.pool_id).collect())
+                        .or_else(|_| {
+                            serde_json::from_str::<FtTransferCallArgsHotZap>(&call.msg).map(
+                                |call| {
+                                    call.hot_zap_actions
+                                        .into_iter()
+                                        .map(|a| a.pool_id)
+                                        .collect()
+                                },
+                            )
+                        })
+                        .ok()
+                })
+                .unwrap_or_default(),
+            _ => Vec::<u64>::new(),
+        };
+        pools.extend(pool_ids.into_iter().map(create_ref_pool_id));
+    }
+    pools
+}
+
+/// Logs "514844781930897970949 shares of liquidity removed: receive back ["1000312838374558764552331 wrap.near", "15865198314126424586378752 intel.tkn.near"]"
+/// as the removed shares and the withdrawn amount of each token
+fn parse_liquidity_removed_log(log: &str) -> Option<(Balance, HashMap<AccountId, Balance>)> {
+    let (shares, tokens) = log.split_once(" shares of liquidity removed: receive back [\"")?;
+    let shares = shares.parse::<Balance>().ok()?;
+    let tokens = tokens.strip_suffix("\"]")?;
+    let mut amounts = HashMap::new();
+    for token in tokens.split("\", \"") {
+        let (amount, token) = token.split_once(' ')?;
+        let amount = amount.parse::<Balance>().ok()?;
+        amounts.insert(canonicalize_account_id(token)?, amount);
+    }
+    Some((shares, amounts))
+}
+
+/// Logs "LP alice.near removed 1000000000000000000 shares by given tokens, and fee is 300000000000000 shares"
+/// of `remove_liquidity_by_tokens` as the burned shares and the fee in shares
+pub(crate) fn parse_liquidity_removed_by_tokens_log(log: &str) -> Option<(Balance, Balance)> {
+    let log = log.strip_prefix("LP ")?;
+    let (_account_id, log) = log.split_once(" removed ")?;
+    let log = log.strip_suffix(" shares")?;
+    let (burned_shares, fee_shares) = log.split_once(" shares by given tokens, and fee is ")?;
+    Some((burned_shares.parse().ok()?, fee_shares.parse().ok()?))
+}
+
+/// `remove_liquidity_by_tokens` calls in a successful Ref receipt, with the fee in
+/// shares that the pool charged. Only stable and rated pools have this method,
+/// proportional `remove_liquidity` is free.
+pub fn liquidity_removal_fees(
+    receipt: &TransactionReceipt,
+    is_testnet: bool,
+) -> Vec<(PoolId, Balance)> {
+    let ref_contract_id = if is_testnet {
+        TESTNET_REF_CONTRACT_ID
+    } else {
+        REF_CONTRACT_ID
+    };
+    if !receipt.is_successful(false) || receipt.receipt.receipt.receiver_id != ref_contract_id {
+        return Vec::new();
+    }
+    let ReceiptEnumView::Action { actions, .. } = &receipt.receipt.receipt.receipt else {
+        return Vec::new();
+    };
+    let mut fees = Vec::new();
+    for action in actions {
+        let ActionView::FunctionCall {
+            method_name, args, ..
+        } = action
+        else {
+            continue;
+        };
+        if method_name != "remove_liquidity_by_tokens" {
+            continue;
+        }
+        let Ok(call) = serde_json::from_slice::<RemoveLiquidityByTokens>(args) else {
+            continue;
+        };
+        fees.extend(
+            receipt
+                .receipt
+                .execution_outcome
+                .outcome
+                .logs
+                .iter()
+                .filter_map(|log| parse_liquidity_removed_by_tokens_log(log))
+                .filter(|(_burned_shares, fee_shares)| *fee_shares > 0)
+                .map(|(_burned_shares, fee_shares)| (create_ref_pool_id(call.pool_id), fee_shares)),
+        );
+    }
+    fees
+}
+
+/// Logs "Liquidity added ["999999999999999915648607 wrap.near", "15869989324782287999975226 intel.tkn.near"], minted 514844781930897970949 shares"
+/// as the added amount of each token
+fn parse_liquidity_added_log(log: &str) -> Option<HashMap<AccountId, i128>> {
+    let log = log.strip_prefix("Liquidity added [\"")?;
+    let log = log.strip_suffix(" shares")?;
+    let (amounts, shares) = log.split_once("\"], minted ")?;
+    let _shares = shares.parse::<Balance>().ok()?;
+    let mut tokens = HashMap::new();
+    for amount in amounts.split("\", \"") {
+        let (amount, token) = amount.split_once(' ')?;
+        let amount = amount.parse::<Balance>().ok()?;
+        let token = canonicalize_account_id(token)?;
+        tokens.insert(token, amount as i128);
+    }
+    Some(tokens)
+}
+
+/// Liquidity logs of a zap receipt and of the Ref receipts it spawned
+fn zap_liquidity_logs<'a>(
+    transaction: &'a IncompleteTransaction,
+    receipt: &'a TransactionReceipt,
+) -> impl Iterator<Item = &'a String> {
+    let child_ids = &receipt.receipt.execution_outcome.outcome.receipt_ids;
+    std::iter::once(receipt)
+        .chain(
+            transaction
+                .receipts
+                .iter()
+                .filter_map(|(_, r)| r.as_ref())
+                .filter(move |r| {
+                    child_ids.contains(&r.receipt.receipt.receipt_id)
+                        && r.receipt.receipt.receiver_id == receipt.receipt.receipt.receiver_id
+                        && r.is_successful(false)
+                }),
+        )
+        .flat_map(|r| r.receipt.execution_outcome.outcome.logs.iter())
+        .filter(|log| log.starts_with("Liquidity added"))
+}
+
+/// Withdrawing LP shares staked in a farm only moves them from the farm back to the
+/// user's Ref account, the pool's liquidity doesn't change. Actual liquidity removals
+/// happen on the exchange contract and log "shares of liquidity removed".
+pub async fn detect_farm_seed_withdrawals(
+    receipt: &TransactionReceipt,
+    transaction: &IncompleteTransaction,
+    block: &StreamerMessage,
+    handler: &mut impl TradeEventHandler,
+    is_testnet: bool,
+) {
+    if is_testnet
+        || !receipt.is_successful(false)
+        || receipt.receipt.receipt.receiver_id != REF_FARM_CONTRACT_ID
+    {
+        return;
+    }
+    let ReceiptEnumView::Action { actions, .. } = &receipt.receipt.receipt.receipt else {
+        return;
+    };
+    for action in actions {
+        let ActionView::FunctionCall {
+            method_name, args, ..
+        } = action
+        else {
+            continue;
+        };
+        if method_name != "withdraw_seed" {
+            continue;
+        }
+        let Ok(call) = serde_json::from_slice::<WithdrawSeed>(args) else {
+            continue;
+        };
+        // LP share seeds are "<exchange>@<pool id>", other seeds are plain tokens
+        let Some(Ok(pool_id)) = call
+            .seed_id
+            .strip_prefix(REF_CONTRACT_ID)
+            .and_then(|seed| seed.strip_prefix('@'))
+            .map(str::parse::<u64>)
+        else {
+            continue;
+        };
+        let trader = receipt.receipt.receipt.predecessor_id.clone();
+        if !is_valid_trader(&trader, receipt) {
+            continue;
+        }
+        handler
+            .on_farm_seed_withdrawn(
+                TradeContext {
+                    trader: trader.clone(),
+                    block_height: block.block.header.height,
+                    block_timestamp_nanosec: block.block.header.timestamp_nanosec as u128,
+                    transaction_id: transaction.transaction.transaction.hash,
+                    receipt_id: receipt.receipt.receipt.receipt_id,
+                },
+                create_ref_pool_id(pool_id),
+                call.amount,
+                trader,
+            )
+            .await;
+    }
+}
+
+/// Tokens deposited to the Ref internal balance with an empty `ft_transfer_call`
+/// message, and tokens withdrawn from it with `withdraw`
+pub async fn detect_deposits_and_withdrawals(
+    receipt: &TransactionReceipt,
+    transaction: &IncompleteTransaction,
+    block: &StreamerMessage,
+    handler: &mut impl TradeEventHandler,
+    is_testnet: bool,
+) {
+    let ref_contract_id = if is_testnet {
+        TESTNET_REF_CONTRACT_ID
+    } else {
+        REF_CONTRACT_ID
+    };
+    if !receipt.is_successful(false) || receipt.receipt.receipt.receiver_id != ref_contract_id {
+        return;
+    }
+    let ReceiptEnumView::Action { actions, .. } = &receipt.receipt.receipt.receipt else {
+        return;
+    };
+    let context = |trader: &AccountId| TradeContext {
+        trader: trader.clone(),
+        block_height: block.block.header.height,
+        block_timestamp_nanosec: block.block.header.timestamp_nanosec as u128,
+        transaction_id: transaction.transaction.transaction.hash,
+        receipt_id: receipt.receipt.receipt.receipt_id,
+    };
+    for action in actions {
+        let ActionView::FunctionCall {
+            method_name, args, ..
+        } = action
+        else {
+            continue;
+        };
+        match method_name.as_str() {
+            "ft_on_transfer" => {
+                let Ok(call) = serde_json::from_slice::<FtOnTransferDeposit>(args) else {
+                    continue;
+                };
+                // Non-empty messages are swaps or liquidity, handled in `detect`
+                if !call.msg.is_empty() || !is_valid_trader(&call.sender_id, receipt) {
+                    continue;
+                }
+                let token = receipt.receipt.receipt.predecessor_id.clone();
+                handler
+                    .on_ref_deposit(
+                        call.sender_id.clone(),
+                        token,
+                        call.amount,
+                        context(&call.sender_id),
+                    )
+                    .await;
+            }
+            "withdraw" => {
+                let Ok(call) = serde_json::from_slice::<Withdraw>(args) else {
+                    continue;
+                };
+                let trader = &receipt.receipt.receipt.predecessor_id;
+                if !is_valid_trader(trader, receipt) {
+                    continue;
+                }
+                // 0 withdraws the whole balance, the amount is in the transfer
+                let amount = if call.amount == 0 {
+                    withdrawn_token_amount(transaction, receipt, &call.token_id)
+                } else {
+                    Some(call.amount)
+                };
+                let Some(amount) = amount else {
+                    log::warn!(
+                        "Couldn't find the amount withdrawn in withdraw receipt {}",
+                        receipt.receipt.receipt.receipt_id
+                    );
+                    continue;
+                };
+                handler
+                    .on_ref_withdraw(trader.clone(), call.token_id, amount, context(trader))
+                    .await;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Amount of the transfer from `receipt` to the token contract
+fn withdrawn_token_amount(
+    transaction: &IncompleteTransaction,
+    receipt: &TransactionReceipt,
+    token_id: &AccountId,
+) -> Option<Balance> {
+    let child_ids = &receipt.receipt.execution_outcome.outcome.receipt_ids;
+    transaction
+        .receipts
+        .iter()
+        .filter_map(|(_, r)| r.as_ref())
+        .filter(|r| {
+            child_ids.contains(&r.receipt.receipt.receipt_id)
+                && r.receipt.receipt.receiver_id == *token_id
+        })
+        .find_map(|r| match &r.receipt.receipt.receipt {
+            ReceiptEnumView::Action { actions, .. } => actions.iter().find_map(|action| {
+                if let ActionView::FunctionCall { args, .. } = action {
+                    serde_json::from_slice::<TokenTransfer>(args)
+                        .ok()
+                        .map(|transfer| transfer.amount)
+                } else {
+                    None
+                }
+            }),
+            _ => None,
+        })
+}
+
+/// Deposits through `ft_transfer_call` where Ref returned part of the amount as
+/// unused, which the token contract then refunds to the sender in
+/// `ft_resolve_transfer`
+pub async fn detect_deposit_refunds(
+    receipt: &TransactionReceipt,
+    transaction: &IncompleteTransaction,
+    block: &StreamerMessage,
+    handler: &mut impl TradeEventHandler,
+    is_testnet: bool,
+) {
+    let ref_contract_id: AccountId = if is_testnet {
+        TESTNET_REF_CONTRACT_ID
+    } else {
+        REF_CONTRACT_ID
+    }
+    .parse()
+    .unwrap();
+    if !receipt.is_successful(false) {
+        return;
+    }
+    let Some(trader) = ft_on_transfer_sender(receipt, &ref_contract_id) else {
+        return;
+    };
+    let ExecutionStatusView::SuccessValue(value) =
+        &receipt.receipt.execution_outcome.outcome.status
+    else {
+        return;
+    };
+    // ft_on_transfer returns the unused amount as a U128 string
+    let Some(refunded_amount) = serde_json::from_slice::<String>(value)
+        .ok()
+        .and_then(|amount| amount.parse::<Balance>().ok())
+    else {
+        return;
+    };
+    if refunded_amount == 0 || !is_valid_trader(&trader, receipt) {
+        return;
+    }
+    let token = receipt.receipt.receipt.predecessor_id.clone();
+    handler
+        .on_deposit_refunded(
+            trader.clone(),
+            token,
+            refunded_amount,
+            TradeContext {
+                trader,
+                block_height: block.block.header.height,
+                block_timestamp_nanosec: block.block.header.timestamp_nanosec as u128,
+                transaction_id: transaction.transaction.transaction.hash,
+                receipt_id: receipt.receipt.receipt.receipt_id,
+            },
+        )
+        .await;
+}
+
+/// Ownership and guardian changes of the Ref contract. Only the owner can call
+/// these, so the caller is the previous owner.
+pub async fn detect_admin_changes(
+    receipt: &TransactionReceipt,
+    transaction: &IncompleteTransaction,
+    block: &StreamerMessage,
+    handler: &mut impl TradeEventHandler,
+    is_testnet: bool,
+) {
+    let ref_contract_id = if is_testnet {
+        TESTNET_REF_CONTRACT_ID
+    } else {
+        REF_CONTRACT_ID
+    };
+    if !receipt.is_successful(false) || receipt.receipt.receipt.receiver_id != ref_contract_id {
+        return;
+    }
+    let ReceiptEnumView::Action { actions, .. } = &receipt.receipt.receipt.receipt else {
+        return;
+    };
+    let caller = &receipt.receipt.receipt.predecessor_id;
+    let context = TradeContext {
+        trader: caller.clone(),
+        block_height: block.block.header.height,
+        block_timestamp_nanosec: block.block.header.timestamp_nanosec as u128,
+        transaction_id: transaction.transaction.transaction.hash,
+        receipt_id: receipt.receipt.receipt.receipt_id,
+    };
+    for action in actions {
+        let ActionView::FunctionCall {
+            method_name, args, ..
+        } = action
+        else {
+            continue;
+        };
+        match method_name.as_str() {
+            "set_owner" => {
+                let Ok(call) = serde_json::from_slice::<SetOwner>(args) else {
+                    continue;
+                };
+                handler
+                    .on_owner_changed(caller.clone(), call.owner_id, context.clone())
+                    .await;
+            }
+            "extend_guardians" | "remove_guardians" => {
+                let Ok(call) = serde_json::from_slice::<ChangeGuardians>(args) else {
+                    continue;
+                };
+                let is_added = method_name == "extend_guardians";
+                for guardian in call.guardians {
+                    let (old_guardian, new_guardian) = if is_added {
+                        (None, Some(guardian))
+                    } else {
+                        (Some(guardian), None)
+                    };
+                    handler
+                        .on_guardian_changed(old_guardian, new_guardian, context.clone())
+                        .await;
+                }
+            }
+            _ => {}
+        }
     }
 }
 
@@ -313,6 +1026,60 @@ pub fn create_ref_pool_id(pool_id: u64) -> PoolId {
     format!("REF-{}", pool_id)
 }
 
+/// Formats an Aurora EVM address as `0x<hex>.aurora`, so that traders coming from
+/// Aurora still have a NEAR account ID
+fn aurora_account_id(evm_address: &str) -> Option<AccountId> {
+    let hex = evm_address
+        .strip_prefix("0x")
+        .unwrap_or(evm_address)
+        .to_lowercase();
+    if hex.len() != 40 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    format!("0x{hex}.aurora").parse().ok()
+}
+
+/// Walks up the receipt tree to the receipt that was executed on `aggregator` and
+/// returns who called it, skipping the aggregator's own callbacks.
+fn find_aggregator_caller(
+    transaction: &IncompleteTransaction,
+    receipt: &TransactionReceipt,
+    aggregator: &AccountId,
+) -> Option<AccountId> {
+    let mut receipt = receipt;
+    while let Some(parent) = find_parent_receipt(transaction, receipt) {
+        if parent.receipt.receipt.receiver_id == *aggregator
+            && parent.receipt.receipt.predecessor_id != *aggregator
+        {
+            return Some(parent.receipt.receipt.predecessor_id.clone());
+        }
+        receipt = parent;
+    }
+    None
+}
+
+/// If `receipt` is an `ft_on_transfer` call on `receiver_id`, returns the account
+/// that transferred the tokens.
+fn ft_on_transfer_sender(
+    receipt: &TransactionReceipt,
+    receiver_id: &AccountId,
+) -> Option<AccountId> {
+    if receipt.receipt.receipt.receiver_id != *receiver_id {
+        return None;
+    }
+    let ReceiptEnumView::Action { actions, .. } = &receipt.receipt.receipt.receipt else {
+        return None;
+    };
+    actions.iter().find_map(|action| match action {
+        ActionView::FunctionCall {
+            method_name, args, ..
+        } if method_name == "ft_on_transfer" => serde_json::from_slice::<FtOnTransferArgs>(args)
+            .ok()
+            .map(|args| args.sender_id),
+        _ => None,
+    })
+}
+
 #[derive(Deserialize, Debug)]
 struct MethodSwap {
     actions: Vec<Action>,
@@ -326,6 +1093,13 @@ struct MethodSwapByOutput {
 #[derive(Deserialize, Debug)]
 struct MethodExecuteActions {
     actions: Vec<Action>,
+    #[serde(default)]
+    referral_id: Option<AccountId>,
+}
+
+#[derive(Deserialize, Debug)]
+struct FtOnTransferArgs {
+    sender_id: AccountId,
 }
 
 #[derive(Deserialize, Debug)]
@@ -337,11 +1111,17 @@ struct FtTransferCallArgs {
 #[derive(Deserialize, Debug)]
 struct FtTransferCallArgsExecute {
     actions: Vec<Action>,
+    /// EVM address of the user when the tokens are sent from Aurora
+    #[serde(default)]
+    aurora_sender: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
 struct FtTransferCallArgsHotZap {
     hot_zap_actions: Vec<Action>,
+    /// Where the zap output goes if not to the caller, e.g. a farm contract
+    #[serde(default)]
+    output_to: Option<AccountId>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -352,6 +1132,65 @@ struct FtTransferCallArgsAddLiquidity {
     amounts: Vec<Balance>,
 }
 
+#[derive(Deserialize, Debug)]
+struct FtOnTransferDeposit {
+    sender_id: AccountId,
+    #[serde(with = "dec_format")]
+    amount: Balance,
+    msg: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Withdraw {
+    token_id: AccountId,
+    #[serde(with = "dec_format")]
+    amount: Balance,
+}
+
+/// `ft_transfer` or `near_withdraw` from Ref to the token contract
+#[derive(Deserialize, Debug)]
+struct TokenTransfer {
+    #[serde(with = "dec_format")]
+    amount: Balance,
+}
+
+#[derive(Deserialize, Debug)]
+struct SetOwner {
+    owner_id: AccountId,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChangeGuardians {
+    guardians: Vec<AccountId>,
+}
+
+#[derive(Deserialize, Debug)]
+struct WithdrawSeed {
+    seed_id: String,
+    #[serde(with = "dec_format")]
+    amount: Balance,
+}
+
+#[derive(Deserialize, Debug)]
+struct NearWithdraw {
+    #[serde(with = "dec_format")]
+    amount: Balance,
+}
+
+#[derive(Deserialize, Debug)]
+struct StorageWithdraw {
+    #[serde(with = "dec_format", default)]
+    amount: Option<Balance>,
+}
+
+#[derive(Deserialize, Debug)]
+struct MftTransferCall {
+    token_id: String,
+    receiver_id: AccountId,
+    #[serde(with = "dec_format")]
+    amount: Balance,
+}
+
 #[derive(Deserialize, Debug)]
 struct RemoveLiquidity {
     pool_id: u64,
@@ -363,6 +1202,17 @@ struct RemoveLiquidity {
     min_amounts: Vec<Balance>,
 }
 
+#[derive(Deserialize, Debug)]
+struct RemoveLiquidityByTokens {
+    pool_id: u64,
+    #[serde(with = "dec_format_vec")]
+    #[allow(dead_code)]
+    amounts: Vec<Balance>,
+    #[serde(with = "dec_format")]
+    #[allow(dead_code)]
+    max_burn_shares: Balance,
+}
+
 #[derive(Deserialize, Debug)]
 #[allow(dead_code)]
 struct Action {
@@ -386,3 +1236,122 @@ struct SwapByOutputAction {
     #[serde(with = "dec_format", default)]
     max_amount_in: Option<Balance>,
 }
+
+/// Moves the wNEAR part of `balance_changes` that the trader wrapped with
+/// `near_deposit` earlier in the transaction, or that Ref unwrapped with
+/// `near_withdraw` after the swap, to native NEAR. Only as much as the swap
+/// spent or received is moved.
+fn apply_native_near_changes(
+    transaction: &IncompleteTransaction,
+    receipt: &TransactionReceipt,
+    trader: &AccountId,
+    wrap_near_contract_id: &str,
+    balance_changes: &mut HashMap<AccountId, i128>,
+) {
+    let Ok(wrap_near) = wrap_near_contract_id.parse::<AccountId>() else {
+        return;
+    };
+    let wnear_change = balance_changes.get(&wrap_near).copied().unwrap_or(0);
+    let mut native_change = 0;
+    if wnear_change < 0 {
+        let wrapped = transaction
+            .receipts
+            .iter()
+            .filter_map(|(_, r)| r.as_ref())
+            .filter(|r| {
+                r.receipt.receipt.receiver_id == wrap_near
+                    && r.receipt.receipt.predecessor_id == *trader
+                    && r.is_successful(false)
+            })
+            .filter_map(|r| match &r.receipt.receipt.receipt {
+                ReceiptEnumView::Action { actions, .. } => Some(actions),
+                _ => None,
+            })
+            .flatten()
+            .filter_map(|action| match action {
+                ActionView::FunctionCall {
+                    method_name,
+                    deposit,
+                    ..
+                } if method_name == "near_deposit" => Some(*deposit as i128),
+                _ => None,
+            })
+            .sum::<i128>();
+        native_change = -wrapped.min(-wnear_change);
+    } else if wnear_change > 0 {
+        let unwrapped = transaction
+            .receipts
+            .iter()
+            .filter_map(|(_, r)| r.as_ref())
+            .filter(|r| {
+                r.receipt.receipt.receiver_id == wrap_near
+                    && r.receipt.receipt.predecessor_id == receipt.receipt.receipt.receiver_id
+                    && r.is_successful(false)
+                    && is_descendant_of(transaction, r, receipt)
+            })
+            .filter_map(|r| match &r.receipt.receipt.receipt {
+                ReceiptEnumView::Action { actions, .. } => Some(actions),
+                _ => None,
+            })
+            .flatten()
+            .filter_map(|action| match action {
+                ActionView::FunctionCall {
+                    method_name, args, ..
+                } if method_name == "near_withdraw" => serde_json::from_slice::<NearWithdraw>(args)
+                    .ok()
+                    .map(|call| call.amount as i128),
+                _ => None,
+            })
+            .sum::<i128>();
+        native_change = unwrapped.min(wnear_change);
+    }
+    if native_change != 0 {
+        *balance_changes.entry(wrap_near).or_insert(0) -= native_change;
+        *balance_changes
+            .entry(NATIVE_NEAR_TOKEN_ID.parse().unwrap())
+            .or_insert(0) += native_change;
+    }
+}
+
+fn is_descendant_of(
+    transaction: &IncompleteTransaction,
+    receipt: &TransactionReceipt,
+    ancestor: &TransactionReceipt,
+) -> bool {
+    let mut current = receipt;
+    while let Some(parent) = find_parent_receipt(transaction, current) {
+        if parent.receipt.receipt.receipt_id == ancestor.receipt.receipt.receipt_id {
+            return true;
+        }
+        current = parent;
+    }
+    false
+}
+
+/// NEAR sent back to `account_id` by the child receipts of `receipt`, if they're
+/// already known
+fn withdrawn_near(
+    transaction: &IncompleteTransaction,
+    receipt: &TransactionReceipt,
+    account_id: &AccountId,
+) -> Option<Balance> {
+    let child_ids = &receipt.receipt.execution_outcome.outcome.receipt_ids;
+    transaction
+        .receipts
+        .iter()
+        .filter_map(|(_, r)| r.as_ref())
+        .filter(|r| {
+            child_ids.contains(&r.receipt.receipt.receipt_id)
+                && r.receipt.receipt.receiver_id == *account_id
+        })
+        .find_map(|r| match &r.receipt.receipt.receipt {
+            ReceiptEnumView::Action { actions, .. } => actions.iter().find_map(|action| {
+                if let ActionView::Transfer { deposit } = action {
+                    Some(*deposit)
+                } else {
+                    None
+                }
+            }),
+            _ => None,
+        })
+}
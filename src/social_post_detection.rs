@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+
+use inindexer::{
+    near_indexer_primitives::{
+        types::AccountId,
+        views::{ActionView, ReceiptEnumView},
+        StreamerMessage,
+    },
+    IncompleteTransaction, TransactionReceipt,
+};
+use serde::Deserialize;
+
+use crate::{is_valid_trader, TradeContext, TradeEventHandler};
+
+pub const SOCIAL_CONTRACT_ID: &str = "social.near";
+
+pub async fn detect(
+    receipt: &TransactionReceipt,
+    transaction: &IncompleteTransaction,
+    block: &StreamerMessage,
+    handler: &mut impl TradeEventHandler,
+    is_testnet: bool,
+) {
+    if is_testnet
+        || !receipt.is_successful(false)
+        || receipt.receipt.receipt.receiver_id != SOCIAL_CONTRACT_ID
+    {
+        return;
+    }
+    let ReceiptEnumView::Action { actions, .. } = &receipt.receipt.receipt.receipt else {
+        return;
+    };
+    let author = &receipt.receipt.receipt.predecessor_id;
+    for action in actions {
+        let ActionView::FunctionCall {
+            method_name, args, ..
+        } = action
+        else {
+            continue;
+        };
+        if method_name != "set" {
+            continue;
+        }
+        let Ok(call) = serde_json::from_slice::<SocialSet>(args) else {
+            continue;
+        };
+        // Accounts can only write to their own key
+        let Some(post) = call
+            .data
+            .get(author)
+            .and_then(|data| data.post.as_ref())
+            .and_then(|post| post.main.as_ref())
+        else {
+            continue;
+        };
+        if !is_valid_trader(author, receipt) {
+            continue;
+        }
+        let content = serde_json::from_str::<PostMain>(post)
+            .map(|main| main.text)
+            .unwrap_or_else(|_| post.clone());
+        handler
+            .on_social_post(
+                TradeContext {
+                    trader: author.clone(),
+                    block_height: block.block.header.height,
+                    block_timestamp_nanosec: block.block.header.timestamp_nanosec as u128,
+                    transaction_id: transaction.transaction.transaction.hash,
+                    receipt_id: receipt.receipt.receipt.receipt_id,
+                },
+                content,
+            )
+            .await;
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct SocialSet {
+    data: HashMap<AccountId, SocialAccountData>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SocialAccountData {
+    post: Option<SocialPost>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SocialPost {
+    /// Json string that represents PostMain
+    main: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PostMain {
+    text: String,
+}
@@ -1,5 +1,7 @@
 use async_trait::async_trait;
-use inindexer::near_indexer_primitives::types::BlockHeight;
+use borsh::BorshDeserialize;
+use inindexer::near_indexer_primitives::types::{Balance, BlockHeight};
+use inindexer::near_indexer_primitives::CryptoHash;
 use intear_events::events::trade::trade_pool_change::AidolsPool;
 use std::collections::HashMap;
 
@@ -8,10 +10,25 @@ use inindexer::{
     BlockIterator, IndexerOptions, PreprocessTransactionsSettings,
 };
 
+use crate::aidols_state::{pool_key_token_id, AidolsPoolState, POOL_KEY_PREFIXES};
+use crate::aidols_trade_detection::{create_aidols_pool_id, net_balance_changes};
+use crate::dragonbot_grouping::GroupDragonbotSwaps;
 use crate::meme_cooking_deposit_detection::{DepositEvent, WithdrawEvent};
+#[cfg(feature = "rpc")]
+use crate::metadata_cache::{FtMetadata, MetadataCache};
+use crate::pool_id_utils::{parse_pool_id, ParsedPoolId};
+use crate::redis_handler::{CandleInterval, RedisKeys};
+use crate::ref_trade_detection::{
+    create_ref_pool_id, parse_liquidity_removed_by_tokens_log, swap_chain_breaks,
+};
+use crate::social_correlation::SocialCorrelator;
+use crate::token_validation::TokenValidator;
+use crate::usd_oracle::{OracleMode, UsdOracle};
 use crate::{
-    ref_finance_state, BalanceChangeSwap, PoolChangeEvent, PoolId, PoolType, RawPoolSwap,
-    TradeContext, TradeEventHandler, TradeIndexer,
+    canonicalize_account_id, coalesce_pool_changes, estimate_tvl_near, invalid_pool_token,
+    ref_finance_state, sample_pool_changes, AidolsPoolDiff, BalanceChangeSwap, BridgeContract,
+    FeeBreakdown, PoolChangeEvent, PoolId, PoolType, RawPoolSwap, TradeContext, TradeEventHandler,
+    TradeIndexer,
 };
 
 #[derive(Default)]
@@ -22,6 +39,8 @@ struct TestHandler {
     memecooking_deposits: Vec<(DepositEvent, TradeContext)>,
     memecooking_withdraws: Vec<(WithdrawEvent, TradeContext)>,
     liquidity_pool_events: Vec<(TradeContext, PoolId, HashMap<AccountId, i128>)>,
+    commissions: Vec<(TradeContext, PoolId, Balance, Balance)>,
+    social_trade_posts: Vec<(AccountId, String, BalanceChangeSwap)>,
 }
 
 #[async_trait]
@@ -65,6 +84,26 @@ impl TradeEventHandler for TestHandler {
         self.liquidity_pool_events.push((context, pool_id, tokens));
     }
 
+    async fn on_commission_collected(
+        &mut self,
+        context: TradeContext,
+        pool_id: PoolId,
+        commission: Balance,
+        total_commission: Balance,
+    ) {
+        self.commissions
+            .push((context, pool_id, commission, total_commission));
+    }
+
+    async fn on_social_trade_post(
+        &mut self,
+        trader: AccountId,
+        post_content: String,
+        swap: BalanceChangeSwap,
+    ) {
+        self.social_trade_posts.push((trader, post_content, swap));
+    }
+
     async fn flush_events(&mut self, _block_height: BlockHeight) {
         // No-op for test handler
     }
@@ -72,10 +111,7 @@ impl TradeEventHandler for TestHandler {
 
 #[tokio::test]
 async fn detects_ref_trades() {
-    let mut indexer = TradeIndexer {
-        handler: TestHandler::default(),
-        is_testnet: false,
-    };
+    let mut indexer = TradeIndexer::new(TestHandler::default(), false);
 
     run_indexer(
         &mut indexer,
@@ -104,7 +140,12 @@ async fn detects_ref_trades() {
                 token_in: "wrap.near".parse().unwrap(),
                 token_out: "meek.tkn.near".parse().unwrap(),
                 amount_in: 1000000000000000000000000,
-                amount_out: 93815865650297411273703890521643
+                amount_out: 93815865650297411273703890521643,
+                referral: None,
+                referral_commission: None,
+                fee_breakdown: None,
+                amount_in_usd: None,
+                amount_out_usd: None,
             },
             TradeContext {
                 trader: "skyto.near".parse().unwrap(),
@@ -139,7 +180,12 @@ async fn detects_ref_trades() {
                     token_in: "wrap.near".parse().unwrap(),
                     token_out: "meek.tkn.near".parse().unwrap(),
                     amount_in: 1000000000000000000000000,
-                    amount_out: 93815865650297411273703890521643
+                    amount_out: 93815865650297411273703890521643,
+                    referral: None,
+                    referral_commission: None,
+                    fee_breakdown: None,
+                    amount_in_usd: None,
+                    amount_out_usd: None,
                 }]
             },
             TradeContext {
@@ -159,10 +205,7 @@ async fn detects_ref_trades() {
 
 #[tokio::test]
 async fn detects_ref_multistep_trades() {
-    let mut indexer = TradeIndexer {
-        handler: TestHandler::default(),
-        is_testnet: false,
-    };
+    let mut indexer = TradeIndexer::new(TestHandler::default(), false);
 
     run_indexer(
         &mut indexer,
@@ -192,7 +235,12 @@ async fn detects_ref_multistep_trades() {
                     token_in: "intel.tkn.near".parse().unwrap(),
                     token_out: "wrap.near".parse().unwrap(),
                     amount_in: 137002618695271800286520468,
-                    amount_out: 26780878168917710181181086
+                    amount_out: 26780878168917710181181086,
+                    referral: None,
+                    referral_commission: None,
+                    fee_breakdown: None,
+                    amount_in_usd: None,
+                    amount_out_usd: None,
                 },
                 TradeContext {
                     trader: "williamxx.near".parse().unwrap(),
@@ -212,7 +260,12 @@ async fn detects_ref_multistep_trades() {
                     token_in: "intel.tkn.near".parse().unwrap(),
                     token_out: "wojak.tkn.near".parse().unwrap(),
                     amount_in: 3527689591892726209943536,
-                    amount_out: 134692454322063117313149
+                    amount_out: 134692454322063117313149,
+                    referral: None,
+                    referral_commission: None,
+                    fee_breakdown: None,
+                    amount_in_usd: None,
+                    amount_out_usd: None,
                 },
                 TradeContext {
                     trader: "williamxx.near".parse().unwrap(),
@@ -232,7 +285,12 @@ async fn detects_ref_multistep_trades() {
                     token_in: "wojak.tkn.near".parse().unwrap(),
                     token_out: "wrap.near".parse().unwrap(),
                     amount_in: 134692454322063117313149,
-                    amount_out: 689165024382991682878108
+                    amount_out: 689165024382991682878108,
+                    referral: None,
+                    referral_commission: None,
+                    fee_breakdown: None,
+                    amount_in_usd: None,
+                    amount_out_usd: None,
                 },
                 TradeContext {
                     trader: "williamxx.near".parse().unwrap(),
@@ -269,21 +327,36 @@ async fn detects_ref_multistep_trades() {
                         token_in: "intel.tkn.near".parse().unwrap(),
                         token_out: "wrap.near".parse().unwrap(),
                         amount_in: 137002618695271800286520468,
-                        amount_out: 26780878168917710181181086
+                        amount_out: 26780878168917710181181086,
+                        referral: None,
+                        referral_commission: None,
+                        fee_breakdown: None,
+                        amount_in_usd: None,
+                        amount_out_usd: None,
                     },
                     RawPoolSwap {
                         pool: "REF-4921".to_owned(),
                         token_in: "intel.tkn.near".parse().unwrap(),
                         token_out: "wojak.tkn.near".parse().unwrap(),
                         amount_in: 3527689591892726209943536,
-                        amount_out: 134692454322063117313149
+                        amount_out: 134692454322063117313149,
+                        referral: None,
+                        referral_commission: None,
+                        fee_breakdown: None,
+                        amount_in_usd: None,
+                        amount_out_usd: None,
                     },
                     RawPoolSwap {
                         pool: "REF-4875".to_owned(),
                         token_in: "wojak.tkn.near".parse().unwrap(),
                         token_out: "wrap.near".parse().unwrap(),
                         amount_in: 134692454322063117313149,
-                        amount_out: 689165024382991682878108
+                        amount_out: 689165024382991682878108,
+                        referral: None,
+                        referral_commission: None,
+                        fee_breakdown: None,
+                        amount_in_usd: None,
+                        amount_out_usd: None,
                     }
                 ]
             },
@@ -304,10 +377,7 @@ async fn detects_ref_multistep_trades() {
 
 #[tokio::test]
 async fn detects_ref_dragonbot_trades() {
-    let mut indexer = TradeIndexer {
-        handler: TestHandler::default(),
-        is_testnet: false,
-    };
+    let mut indexer = TradeIndexer::new(TestHandler::default(), false);
 
     run_indexer(
         &mut indexer,
@@ -340,7 +410,12 @@ async fn detects_ref_dragonbot_trades() {
                 token_in: "meek.tkn.near".parse().unwrap(),
                 token_out: "wrap.near".parse().unwrap(),
                 amount_in: 478481220062017777819333235161697,
-                amount_out: 9466638646302120499119272
+                amount_out: 9466638646302120499119272,
+                referral: None,
+                referral_commission: None,
+                fee_breakdown: None,
+                amount_in_usd: None,
+                amount_out_usd: None,
             },
             TradeContext {
                 trader: "kxf05k08ps1ol3zgcwvmkam_dragon.dragon_bot.near"
@@ -381,7 +456,82 @@ async fn detects_ref_dragonbot_trades() {
                     token_in: "meek.tkn.near".parse().unwrap(),
                     token_out: "wrap.near".parse().unwrap(),
                     amount_in: 478481220062017777819333235161697,
-                    amount_out: 9466638646302120499119272
+                    amount_out: 9466638646302120499119272,
+                    referral: None,
+                    referral_commission: None,
+                    fee_breakdown: None,
+                    amount_in_usd: None,
+                    amount_out_usd: None,
+                }]
+            },
+            TradeContext {
+                trader: "kxf05k08ps1ol3zgcwvmkam_dragon.dragon_bot.near"
+                    .parse()
+                    .unwrap(),
+                block_height: 118209236,
+                block_timestamp_nanosec: 1714803352814919506,
+                transaction_id: "C4pr5yYyxviWQkt4K7uVFaH14LWR43gcKpj1GDiV4nc8"
+                    .parse()
+                    .unwrap(),
+                receipt_id: "4xmgsfQ6YypjKC2hxts11YBuRNYjaavShtrpRAWxFHNu"
+                    .parse()
+                    .unwrap(),
+            }
+        )]
+    );
+}
+
+#[tokio::test]
+async fn groups_ref_dragonbot_trades() {
+    let mut indexer = TradeIndexer::new(GroupDragonbotSwaps::new(TestHandler::default()), false);
+
+    run_indexer(
+        &mut indexer,
+        NeardataProvider::mainnet(),
+        IndexerOptions {
+            range: BlockIterator::iterator(118_209_234..=118_209_239),
+            preprocess_transactions: Some(PreprocessTransactionsSettings {
+                prefetch_blocks: 0,
+                postfetch_blocks: 0,
+            }),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+
+    // A transaction with one swap receipt comes out of the grouping unchanged
+    assert_eq!(
+        *indexer
+            .handler()
+            .inner
+            .balance_change_swaps
+            .get(
+                &"kxf05k08ps1ol3zgcwvmkam_dragon.dragon_bot.near"
+                    .parse::<AccountId>()
+                    .unwrap()
+            )
+            .unwrap(),
+        vec![(
+            BalanceChangeSwap {
+                balance_changes: HashMap::from_iter([
+                    ("wrap.near".parse().unwrap(), 9466638646302120499119272),
+                    (
+                        "meek.tkn.near".parse().unwrap(),
+                        -478481220062017777819333235161697
+                    )
+                ]),
+                pool_swaps: vec![RawPoolSwap {
+                    pool: "REF-5059".to_owned(),
+                    token_in: "meek.tkn.near".parse().unwrap(),
+                    token_out: "wrap.near".parse().unwrap(),
+                    amount_in: 478481220062017777819333235161697,
+                    amount_out: 9466638646302120499119272,
+                    referral: None,
+                    referral_commission: None,
+                    fee_breakdown: None,
+                    amount_in_usd: None,
+                    amount_out_usd: None,
                 }]
             },
             TradeContext {
@@ -403,10 +553,7 @@ async fn detects_ref_dragonbot_trades() {
 
 #[tokio::test]
 async fn detects_ref_arbitrage_trades() {
-    let mut indexer = TradeIndexer {
-        handler: TestHandler::default(),
-        is_testnet: false,
-    };
+    let mut indexer = TradeIndexer::new(TestHandler::default(), false);
 
     run_indexer(
         &mut indexer,
@@ -436,7 +583,12 @@ async fn detects_ref_arbitrage_trades() {
                     token_in: "wrap.near".parse().unwrap(),
                     token_out: "token.0xshitzu.near".parse().unwrap(),
                     amount_in: 520000000000000000000000,
-                    amount_out: 3244576408763446222268
+                    amount_out: 3244576408763446222268,
+                    referral: None,
+                    referral_commission: None,
+                    fee_breakdown: None,
+                    amount_in_usd: None,
+                    amount_out_usd: None,
                 },
                 TradeContext {
                     trader: "bot.marior.near".parse().unwrap(),
@@ -456,7 +608,12 @@ async fn detects_ref_arbitrage_trades() {
                     token_in: "token.0xshitzu.near".parse().unwrap(),
                     token_out: "nkok.tkn.near".parse().unwrap(),
                     amount_in: 3244576408763446222268,
-                    amount_out: 11186538717588640655335259
+                    amount_out: 11186538717588640655335259,
+                    referral: None,
+                    referral_commission: None,
+                    fee_breakdown: None,
+                    amount_in_usd: None,
+                    amount_out_usd: None,
                 },
                 TradeContext {
                     trader: "bot.marior.near".parse().unwrap(),
@@ -476,7 +633,12 @@ async fn detects_ref_arbitrage_trades() {
                     token_in: "nkok.tkn.near".parse().unwrap(),
                     token_out: "slush.tkn.near".parse().unwrap(),
                     amount_in: 11186538717588640655335259,
-                    amount_out: 88180050805911386368580
+                    amount_out: 88180050805911386368580,
+                    referral: None,
+                    referral_commission: None,
+                    fee_breakdown: None,
+                    amount_in_usd: None,
+                    amount_out_usd: None,
                 },
                 TradeContext {
                     trader: "bot.marior.near".parse().unwrap(),
@@ -496,7 +658,12 @@ async fn detects_ref_arbitrage_trades() {
                     token_in: "slush.tkn.near".parse().unwrap(),
                     token_out: "wojak.tkn.near".parse().unwrap(),
                     amount_in: 88180050805911386368580,
-                    amount_out: 102552548670451059547623
+                    amount_out: 102552548670451059547623,
+                    referral: None,
+                    referral_commission: None,
+                    fee_breakdown: None,
+                    amount_in_usd: None,
+                    amount_out_usd: None,
                 },
                 TradeContext {
                     trader: "bot.marior.near".parse().unwrap(),
@@ -516,7 +683,12 @@ async fn detects_ref_arbitrage_trades() {
                     token_in: "wojak.tkn.near".parse().unwrap(),
                     token_out: "wrap.near".parse().unwrap(),
                     amount_in: 102552548670451059547623,
-                    amount_out: 525408551701397302192601
+                    amount_out: 525408551701397302192601,
+                    referral: None,
+                    referral_commission: None,
+                    fee_breakdown: None,
+                    amount_in_usd: None,
+                    amount_out_usd: None,
                 },
                 TradeContext {
                     trader: "bot.marior.near".parse().unwrap(),
@@ -550,35 +722,60 @@ async fn detects_ref_arbitrage_trades() {
                         token_in: "wrap.near".parse().unwrap(),
                         token_out: "token.0xshitzu.near".parse().unwrap(),
                         amount_in: 520000000000000000000000,
-                        amount_out: 3244576408763446222268
+                        amount_out: 3244576408763446222268,
+                        referral: None,
+                        referral_commission: None,
+                        fee_breakdown: None,
+                        amount_in_usd: None,
+                        amount_out_usd: None,
                     },
                     RawPoolSwap {
                         pool: "REF-4821".to_owned(),
                         token_in: "token.0xshitzu.near".parse().unwrap(),
                         token_out: "nkok.tkn.near".parse().unwrap(),
                         amount_in: 3244576408763446222268,
-                        amount_out: 11186538717588640655335259
+                        amount_out: 11186538717588640655335259,
+                        referral: None,
+                        referral_commission: None,
+                        fee_breakdown: None,
+                        amount_in_usd: None,
+                        amount_out_usd: None,
                     },
                     RawPoolSwap {
                         pool: "REF-4913".to_owned(),
                         token_in: "nkok.tkn.near".parse().unwrap(),
                         token_out: "slush.tkn.near".parse().unwrap(),
                         amount_in: 11186538717588640655335259,
-                        amount_out: 88180050805911386368580
+                        amount_out: 88180050805911386368580,
+                        referral: None,
+                        referral_commission: None,
+                        fee_breakdown: None,
+                        amount_in_usd: None,
+                        amount_out_usd: None,
                     },
                     RawPoolSwap {
                         pool: "REF-4911".to_owned(),
                         token_in: "slush.tkn.near".parse().unwrap(),
                         token_out: "wojak.tkn.near".parse().unwrap(),
                         amount_in: 88180050805911386368580,
-                        amount_out: 102552548670451059547623
+                        amount_out: 102552548670451059547623,
+                        referral: None,
+                        referral_commission: None,
+                        fee_breakdown: None,
+                        amount_in_usd: None,
+                        amount_out_usd: None,
                     },
                     RawPoolSwap {
                         pool: "REF-4875".to_owned(),
                         token_in: "wojak.tkn.near".parse().unwrap(),
                         token_out: "wrap.near".parse().unwrap(),
                         amount_in: 102552548670451059547623,
-                        amount_out: 525408551701397302192601
+                        amount_out: 525408551701397302192601,
+                        referral: None,
+                        referral_commission: None,
+                        fee_breakdown: None,
+                        amount_in_usd: None,
+                        amount_out_usd: None,
                     }
                 ]
             },
@@ -599,10 +796,7 @@ async fn detects_ref_arbitrage_trades() {
 
 #[tokio::test]
 async fn doesnt_detect_failed_ref_arbitrage_trades() {
-    let mut indexer = TradeIndexer {
-        handler: TestHandler::default(),
-        is_testnet: false,
-    };
+    let mut indexer = TradeIndexer::new(TestHandler::default(), false);
 
     run_indexer(
         &mut indexer,
@@ -637,10 +831,7 @@ async fn doesnt_detect_failed_ref_arbitrage_trades() {
 
 #[tokio::test]
 async fn doesnt_detect_failed_ref_trades() {
-    let mut indexer = TradeIndexer {
-        handler: TestHandler::default(),
-        is_testnet: false,
-    };
+    let mut indexer = TradeIndexer::new(TestHandler::default(), false);
 
     run_indexer(
         &mut indexer,
@@ -675,10 +866,7 @@ async fn doesnt_detect_failed_ref_trades() {
 
 #[tokio::test]
 async fn detects_delegate_ref_trades() {
-    let mut indexer = TradeIndexer {
-        handler: TestHandler::default(),
-        is_testnet: false,
-    };
+    let mut indexer = TradeIndexer::new(TestHandler::default(), false);
 
     run_indexer(
         &mut indexer,
@@ -708,7 +896,12 @@ async fn detects_delegate_ref_trades() {
                     token_in: "usdt.tether-token.near".parse().unwrap(),
                     token_out: "wrap.near".parse().unwrap(),
                     amount_in: 29992989,
-                    amount_out: 4403363405586660846534469
+                    amount_out: 4403363405586660846534469,
+                    referral: None,
+                    referral_commission: None,
+                    fee_breakdown: None,
+                    amount_in_usd: None,
+                    amount_out_usd: None,
                 },
                 TradeContext {
                     trader: "alanmain.near".parse().unwrap(),
@@ -728,7 +921,12 @@ async fn detects_delegate_ref_trades() {
                     token_in: "wrap.near".parse().unwrap(),
                     token_out: "intel.tkn.near".parse().unwrap(),
                     amount_in: 4403363405586660846534469,
-                    amount_out: 43884510175556511587239906
+                    amount_out: 43884510175556511587239906,
+                    referral: None,
+                    referral_commission: None,
+                    fee_breakdown: None,
+                    amount_in_usd: None,
+                    amount_out_usd: None,
                 },
                 TradeContext {
                     trader: "alanmain.near".parse().unwrap(),
@@ -748,7 +946,12 @@ async fn detects_delegate_ref_trades() {
                     token_in: "usdt.tether-token.near".parse().unwrap(),
                     token_out: "intel.tkn.near".parse().unwrap(),
                     amount_in: 11647,
-                    amount_out: 17258755648110183139126
+                    amount_out: 17258755648110183139126,
+                    referral: None,
+                    referral_commission: None,
+                    fee_breakdown: None,
+                    amount_in_usd: None,
+                    amount_out_usd: None,
                 },
                 TradeContext {
                     trader: "alanmain.near".parse().unwrap(),
@@ -785,21 +988,36 @@ async fn detects_delegate_ref_trades() {
                         token_in: "usdt.tether-token.near".parse().unwrap(),
                         token_out: "wrap.near".parse().unwrap(),
                         amount_in: 29992989,
-                        amount_out: 4403363405586660846534469
+                        amount_out: 4403363405586660846534469,
+                        referral: None,
+                        referral_commission: None,
+                        fee_breakdown: None,
+                        amount_in_usd: None,
+                        amount_out_usd: None,
                     },
                     RawPoolSwap {
                         pool: "REF-4663".to_owned(),
                         token_in: "wrap.near".parse().unwrap(),
                         token_out: "intel.tkn.near".parse().unwrap(),
                         amount_in: 4403363405586660846534469,
-                        amount_out: 43884510175556511587239906
+                        amount_out: 43884510175556511587239906,
+                        referral: None,
+                        referral_commission: None,
+                        fee_breakdown: None,
+                        amount_in_usd: None,
+                        amount_out_usd: None,
                     },
                     RawPoolSwap {
                         pool: "REF-4668".to_owned(),
                         token_in: "usdt.tether-token.near".parse().unwrap(),
                         token_out: "intel.tkn.near".parse().unwrap(),
                         amount_in: 11647,
-                        amount_out: 17258755648110183139126
+                        amount_out: 17258755648110183139126,
+                        referral: None,
+                        referral_commission: None,
+                        fee_breakdown: None,
+                        amount_in_usd: None,
+                        amount_out_usd: None,
                     }
                 ]
             },
@@ -820,10 +1038,7 @@ async fn detects_delegate_ref_trades() {
 
 #[tokio::test]
 async fn detects_ref_state_changes() {
-    let mut indexer = TradeIndexer {
-        handler: TestHandler::default(),
-        is_testnet: false,
-    };
+    let mut indexer = TradeIndexer::new(TestHandler::default(), false);
 
     run_indexer(
         &mut indexer,
@@ -841,7 +1056,7 @@ async fn detects_ref_state_changes() {
     .unwrap();
 
     assert_eq!(
-        indexer.handler.state_changes,
+        indexer.handler().state_changes,
         vec![PoolChangeEvent {
             pool_id: "REF-5059".to_owned(),
             receipt_id: "VPrcZiwgFqKgW9eev4CUKJ4TN8Jk1jSZ2sqFAHothnN"
@@ -872,17 +1087,16 @@ async fn detects_ref_state_changes() {
                     shares_prefix: vec![2, 195, 19, 0, 0],
                     shares_total_supply: 1495131888301825452817183
                 }
-            ))
+            )),
+            spot_price: None,
+            sampled: false,
         }]
     );
 }
 
 #[tokio::test]
 async fn detects_ref_hot_tg_trades() {
-    let mut indexer = TradeIndexer {
-        handler: TestHandler::default(),
-        is_testnet: false,
-    };
+    let mut indexer = TradeIndexer::new(TestHandler::default(), false);
 
     run_indexer(
         &mut indexer,
@@ -912,7 +1126,12 @@ async fn detects_ref_hot_tg_trades() {
                     token_in: "dd.tg".parse().unwrap(),
                     token_out: "wrap.near".parse().unwrap(),
                     amount_in: 933200000000,
-                    amount_out: 1694993438147166311514743
+                    amount_out: 1694993438147166311514743,
+                    referral: None,
+                    referral_commission: None,
+                    fee_breakdown: None,
+                    amount_in_usd: None,
+                    amount_out_usd: None,
                 },
                 TradeContext {
                     trader: "acejapan.tg".parse().unwrap(),
@@ -932,7 +1151,12 @@ async fn detects_ref_hot_tg_trades() {
                     token_in: "wrap.near".parse().unwrap(),
                     token_out: "usdt.tether-token.near".parse().unwrap(),
                     amount_in: 1694993438147166311514743,
-                    amount_out: 9458256
+                    amount_out: 9458256,
+                    referral: None,
+                    referral_commission: None,
+                    fee_breakdown: None,
+                    amount_in_usd: None,
+                    amount_out_usd: None,
                 },
                 TradeContext {
                     trader: "acejapan.tg".parse().unwrap(),
@@ -966,14 +1190,24 @@ async fn detects_ref_hot_tg_trades() {
                         token_in: "dd.tg".parse().unwrap(),
                         token_out: "wrap.near".parse().unwrap(),
                         amount_in: 933200000000,
-                        amount_out: 1694993438147166311514743
+                        amount_out: 1694993438147166311514743,
+                        referral: None,
+                        referral_commission: None,
+                        fee_breakdown: None,
+                        amount_in_usd: None,
+                        amount_out_usd: None,
                     },
                     RawPoolSwap {
                         pool: "REF-3879".to_string(),
                         token_in: "wrap.near".parse().unwrap(),
                         token_out: "usdt.tether-token.near".parse().unwrap(),
                         amount_in: 1694993438147166311514743,
-                        amount_out: 9458256
+                        amount_out: 9458256,
+                        referral: None,
+                        referral_commission: None,
+                        fee_breakdown: None,
+                        amount_in_usd: None,
+                        amount_out_usd: None,
                     }
                 ]
             },
@@ -994,10 +1228,7 @@ async fn detects_ref_hot_tg_trades() {
 
 #[tokio::test]
 async fn detects_memecooking_deposits() {
-    let mut indexer = TradeIndexer {
-        handler: TestHandler::default(),
-        is_testnet: true,
-    };
+    let mut indexer = TradeIndexer::new(TestHandler::default(), true);
 
     run_indexer(
         &mut indexer,
@@ -1015,7 +1246,7 @@ async fn detects_memecooking_deposits() {
     .unwrap();
 
     assert_eq!(
-        *indexer.handler.memecooking_deposits,
+        *indexer.handler().memecooking_deposits,
         vec![(
             DepositEvent {
                 meme_id: 52,
@@ -1046,10 +1277,7 @@ async fn detects_memecooking_deposits() {
 
 #[tokio::test]
 async fn detects_memecooking_withdraws() {
-    let mut indexer = TradeIndexer {
-        handler: TestHandler::default(),
-        is_testnet: true,
-    };
+    let mut indexer = TradeIndexer::new(TestHandler::default(), true);
 
     run_indexer(
         &mut indexer,
@@ -1067,7 +1295,7 @@ async fn detects_memecooking_withdraws() {
     .unwrap();
 
     assert_eq!(
-        *indexer.handler.memecooking_withdraws,
+        *indexer.handler().memecooking_withdraws,
         vec![(
             WithdrawEvent {
                 meme_id: 53,
@@ -1092,10 +1320,7 @@ async fn detects_memecooking_withdraws() {
 
 #[tokio::test]
 async fn detects_ref_liquidity_add() {
-    let mut indexer = TradeIndexer {
-        handler: TestHandler::default(),
-        is_testnet: false,
-    };
+    let mut indexer = TradeIndexer::new(TestHandler::default(), false);
 
     run_indexer(
         &mut indexer,
@@ -1113,7 +1338,7 @@ async fn detects_ref_liquidity_add() {
     .unwrap();
 
     assert_eq!(
-        indexer.handler.liquidity_pool_events,
+        indexer.handler().liquidity_pool_events,
         vec![(
             TradeContext {
                 trader: "slimedragon.near".parse().unwrap(),
@@ -1140,10 +1365,7 @@ async fn detects_ref_liquidity_add() {
 
 #[tokio::test]
 async fn detects_ref_liquidity_remove() {
-    let mut indexer = TradeIndexer {
-        handler: TestHandler::default(),
-        is_testnet: false,
-    };
+    let mut indexer = TradeIndexer::new(TestHandler::default(), false);
 
     run_indexer(
         &mut indexer,
@@ -1161,7 +1383,7 @@ async fn detects_ref_liquidity_remove() {
     .unwrap();
 
     assert_eq!(
-        indexer.handler.liquidity_pool_events,
+        indexer.handler().liquidity_pool_events,
         vec![(
             TradeContext {
                 trader: "slimedragon.near".parse().unwrap(),
@@ -1188,10 +1410,7 @@ async fn detects_ref_liquidity_remove() {
 
 #[tokio::test]
 async fn detects_ref_swap_by_output() {
-    let mut indexer = TradeIndexer {
-        handler: TestHandler::default(),
-        is_testnet: false,
-    };
+    let mut indexer = TradeIndexer::new(TestHandler::default(), false);
 
     run_indexer(
         &mut indexer,
@@ -1220,7 +1439,12 @@ async fn detects_ref_swap_by_output() {
                 token_in: "wrap.near".parse().unwrap(),
                 token_out: "intel.tkn.near".parse().unwrap(),
                 amount_in: 706788683547272399546037,
-                amount_out: 14932514982037617660395520
+                amount_out: 14932514982037617660395520,
+                referral: None,
+                referral_commission: None,
+                fee_breakdown: None,
+                amount_in_usd: None,
+                amount_out_usd: None,
             },
             TradeContext {
                 trader: "fiery_drone.user.intear.near".parse().unwrap(),
@@ -1255,7 +1479,12 @@ async fn detects_ref_swap_by_output() {
                     token_in: "wrap.near".parse().unwrap(),
                     token_out: "intel.tkn.near".parse().unwrap(),
                     amount_in: 706788683547272399546037,
-                    amount_out: 14932514982037617660395520
+                    amount_out: 14932514982037617660395520,
+                    referral: None,
+                    referral_commission: None,
+                    fee_breakdown: None,
+                    amount_in_usd: None,
+                    amount_out_usd: None,
                 },]
             },
             TradeContext {
@@ -1275,10 +1504,7 @@ async fn detects_ref_swap_by_output() {
 
 #[tokio::test]
 async fn detects_aidols_buy() {
-    let mut indexer = TradeIndexer {
-        handler: TestHandler::default(),
-        is_testnet: false,
-    };
+    let mut indexer = TradeIndexer::new(TestHandler::default(), false);
 
     run_indexer(
         &mut indexer,
@@ -1307,7 +1533,12 @@ async fn detects_aidols_buy() {
                 token_in: "wrap.near".parse().unwrap(),
                 token_out: "ponkeai.aidols.near".parse().unwrap(),
                 amount_in: 300000000000000000000000,
-                amount_out: 399840063974410235905637744903
+                amount_out: 399840063974410235905637744903,
+                referral: None,
+                referral_commission: None,
+                fee_breakdown: None,
+                amount_in_usd: None,
+                amount_out_usd: None,
             },
             TradeContext {
                 trader: "slimedragon.near".parse().unwrap(),
@@ -1342,7 +1573,12 @@ async fn detects_aidols_buy() {
                     token_in: "wrap.near".parse().unwrap(),
                     token_out: "ponkeai.aidols.near".parse().unwrap(),
                     amount_in: 300000000000000000000000,
-                    amount_out: 399840063974410235905637744903
+                    amount_out: 399840063974410235905637744903,
+                    referral: None,
+                    referral_commission: None,
+                    fee_breakdown: None,
+                    amount_in_usd: None,
+                    amount_out_usd: None,
                 }]
             },
             TradeContext {
@@ -1358,14 +1594,37 @@ async fn detects_aidols_buy() {
             }
         )]
     );
+    let [(context, pool_id, commission, total_commission)] =
+        indexer.handler().commissions.as_slice()
+    else {
+        panic!(
+            "Expected one commission, got {:?}",
+            indexer.handler().commissions
+        );
+    };
+    assert_eq!(
+        *context,
+        TradeContext {
+            trader: "slimedragon.near".parse().unwrap(),
+            block_height: 137406122,
+            block_timestamp_nanosec: 1736934912940183334,
+            transaction_id: "6xNcuGFB3Qs5hmDkavireqsxaENLGeJVw5St8PeXYnDz"
+                .parse()
+                .unwrap(),
+            receipt_id: "3KiybrbFAbDMxcTYDmZpjBrQX7pKLGoMreoHpLa6kEWs"
+                .parse()
+                .unwrap(),
+        }
+    );
+    assert_eq!(pool_id, "AIDOLS-ponkeai.aidols.near");
+    assert!(*commission > 0);
+    // First commission of the pool since the indexer started
+    assert_eq!(total_commission, commission);
 }
 
 #[tokio::test]
 async fn detects_aidols_sell() {
-    let mut indexer = TradeIndexer {
-        handler: TestHandler::default(),
-        is_testnet: false,
-    };
+    let mut indexer = TradeIndexer::new(TestHandler::default(), false);
 
     run_indexer(
         &mut indexer,
@@ -1394,7 +1653,12 @@ async fn detects_aidols_sell() {
                 token_in: "ponkeai.aidols.near".parse().unwrap(),
                 token_out: "wrap.near".parse().unwrap(),
                 amount_in: 399840063974410235905637744903,
-                amount_out: 100000000000000000000001
+                amount_out: 100000000000000000000001,
+                referral: None,
+                referral_commission: None,
+                fee_breakdown: None,
+                amount_in_usd: None,
+                amount_out_usd: None,
             },
             TradeContext {
                 trader: "slimedragon.near".parse().unwrap(),
@@ -1430,7 +1694,12 @@ async fn detects_aidols_sell() {
                     token_in: "ponkeai.aidols.near".parse().unwrap(),
                     token_out: "wrap.near".parse().unwrap(),
                     amount_in: 399840063974410235905637744903,
-                    amount_out: 100000000000000000000001
+                    amount_out: 100000000000000000000001,
+                    referral: None,
+                    referral_commission: None,
+                    fee_breakdown: None,
+                    amount_in_usd: None,
+                    amount_out_usd: None,
                 }],
             },
             TradeContext {
@@ -1450,10 +1719,7 @@ async fn detects_aidols_sell() {
 
 #[tokio::test]
 async fn detects_aidols_state_changes() {
-    let mut indexer = TradeIndexer {
-        handler: TestHandler::default(),
-        is_testnet: false,
-    };
+    let mut indexer = TradeIndexer::new(TestHandler::default(), false);
 
     run_indexer(
         &mut indexer,
@@ -1471,7 +1737,7 @@ async fn detects_aidols_state_changes() {
     .unwrap();
 
     assert!(
-        dbg!(indexer.handler.state_changes).contains(&PoolChangeEvent {
+        dbg!(&indexer.handler().state_changes).contains(&PoolChangeEvent {
             pool_id: "AIDOLS-tganza.aidols.near".to_owned(),
             receipt_id: "ErBeAEQyuWyab7ggYrzEZnPBo1sJA4GnJ6PhiCrMnn9y"
                 .parse()
@@ -1484,7 +1750,951 @@ async fn detects_aidols_state_changes() {
                 wnear_hold: 500000000000000000000000000,
                 is_deployed: false,
                 is_tradable: true
-            })
+            }),
+            spot_price: Some(500000000000000000),
+            sampled: false,
+        })
+    );
+}
+
+#[test]
+fn deserializes_unknown_ref_pool_types() {
+    let bytes = [3, 1, 2, 3];
+    let pool =
+        <ref_finance_state::Pool as BorshDeserialize>::deserialize(&mut bytes.as_slice()).unwrap();
+    assert_eq!(
+        pool,
+        ref_finance_state::Pool::Unknown {
+            discriminant: 3,
+            raw_bytes: vec![1, 2, 3]
+        }
+    );
+    assert_eq!(borsh::to_vec(&pool).unwrap(), bytes);
+}
+
+#[test]
+fn validates_ref_pool_token_counts() {
+    let mut pool = ref_finance_state::SimplePool {
+        token_account_ids: vec!["wrap.near".to_owned(), "intel.tkn.near".to_owned()],
+        amounts: vec![1, 2],
+        volumes: vec![
+            ref_finance_state::SwapVolume {
+                input: 1,
+                output: 1,
+            },
+            ref_finance_state::SwapVolume {
+                input: 2,
+                output: 2,
+            },
+        ],
+        total_fee: 0,
+        exchange_fee: 0,
+        referral_fee: 0,
+        shares_prefix: vec![],
+        shares_total_supply: 0,
+    };
+    pool.volumes.pop();
+    assert!(ref_finance_state::Pool::SimplePool(pool)
+        .validate()
+        .is_err());
+    assert!(ref_finance_state::Pool::Unknown {
+        discriminant: 3,
+        raw_bytes: vec![]
+    }
+    .validate()
+    .is_ok());
+}
+
+#[test]
+fn converts_pool_change_events_to_pools() {
+    let pool = ref_finance_state::Pool::Unknown {
+        discriminant: 3,
+        raw_bytes: vec![],
+    };
+    let event = PoolChangeEvent {
+        pool_id: "REF-0".to_owned(),
+        receipt_id: Default::default(),
+        block_timestamp_nanosec: 0,
+        block_height: 0,
+        pool: PoolType::Ref(pool),
+        spot_price: None,
+        sampled: false,
+    };
+    assert!(event.as_ref_pool().is_some());
+    assert!(event.as_aidols_pool().is_none());
+    let event = AidolsPool::try_from(event).unwrap_err();
+    assert_eq!(
+        ref_finance_state::Pool::try_from(event),
+        Ok(ref_finance_state::Pool::Unknown {
+            discriminant: 3,
+            raw_bytes: vec![]
+        })
+    );
+}
+
+fn test_pool_swap(token_in: &str, token_out: &str) -> RawPoolSwap {
+    RawPoolSwap {
+        pool: "REF-0".to_owned(),
+        token_in: token_in.parse().unwrap(),
+        token_out: token_out.parse().unwrap(),
+        amount_in: 1,
+        amount_out: 1,
+        referral: None,
+        referral_commission: None,
+        fee_breakdown: None,
+        amount_in_usd: None,
+        amount_out_usd: None,
+    }
+}
+
+fn test_route(pool_swaps: Vec<RawPoolSwap>) -> Vec<String> {
+    BalanceChangeSwap {
+        balance_changes: HashMap::new(),
+        pool_swaps,
+    }
+    .route()
+    .into_iter()
+    .map(|token| token.to_string())
+    .collect()
+}
+
+#[test]
+fn builds_single_hop_route() {
+    assert_eq!(
+        test_route(vec![test_pool_swap("wrap.near", "intel.tkn.near")]),
+        vec!["wrap.near", "intel.tkn.near"]
+    );
+}
+
+#[test]
+fn builds_two_hop_route() {
+    assert_eq!(
+        test_route(vec![
+            test_pool_swap("usdt.tether-token.near", "wrap.near"),
+            test_pool_swap("wrap.near", "intel.tkn.near"),
+        ]),
+        vec!["usdt.tether-token.near", "wrap.near", "intel.tkn.near"]
+    );
+}
+
+#[test]
+fn builds_arbitrage_route() {
+    assert_eq!(
+        test_route(vec![
+            test_pool_swap("wrap.near", "intel.tkn.near"),
+            test_pool_swap("intel.tkn.near", "usdt.tether-token.near"),
+            test_pool_swap("usdt.tether-token.near", "wrap.near"),
+        ]),
+        vec![
+            "wrap.near",
+            "intel.tkn.near",
+            "usdt.tether-token.near",
+            "wrap.near"
+        ]
+    );
+}
+
+#[test]
+fn deserializes_known_aidols_state_versions() {
+    let state = AidolsPoolState {
+        token_hold: 1000000000000000000000000000000000,
+        wnear_hold: 500000000000000000000000000,
+        is_deployed: false,
+        is_tradable: true,
+    };
+    let bytes = borsh::to_vec(&state).unwrap();
+    assert_eq!(
+        AidolsPoolState::deserialize_versioned(&bytes, 1),
+        Some((state, 1))
+    );
+    // A future preferred version falls back to the known layouts
+    assert_eq!(
+        AidolsPoolState::deserialize_versioned(&bytes, 2).map(|(_, version)| version),
+        Some(1)
+    );
+    // Truncated or reshuffled states don't match any layout
+    assert_eq!(
+        AidolsPoolState::deserialize_versioned(&bytes[..20], 1),
+        None
+    );
+}
+
+#[test]
+fn parses_created_pool_ids() {
+    for pool_id in [0, 1, 4242, u64::MAX] {
+        assert_eq!(
+            parse_pool_id(&create_ref_pool_id(pool_id)),
+            Some(ParsedPoolId::Ref(pool_id))
+        );
+    }
+    for token_id in ["intel.tkn.near", "a.b.aidols.near", "0x1234.aurora"] {
+        let token_id: AccountId = token_id.parse().unwrap();
+        assert_eq!(
+            parse_pool_id(&create_aidols_pool_id(&token_id)),
+            Some(ParsedPoolId::Aidols(token_id))
+        );
+    }
+    assert_eq!(parse_pool_id("REF-abc"), None);
+    assert_eq!(parse_pool_id("UNKNOWN-1"), None);
+}
+
+#[tokio::test]
+async fn groups_dragonbot_swaps_in_one_transaction() {
+    let mut handler = GroupDragonbotSwaps::new(TestHandler::default());
+    let trader: AccountId = "user.dragon_bot.near".parse().unwrap();
+    let context = TradeContext {
+        trader: trader.clone(),
+        block_height: 1,
+        block_timestamp_nanosec: 0,
+        transaction_id: Default::default(),
+        receipt_id: Default::default(),
+    };
+    let first = test_pool_swap("usdt.tether-token.near", "wrap.near");
+    let second = test_pool_swap("wrap.near", "intel.tkn.near");
+    handler
+        .on_balance_change_swap(
+            context.clone(),
+            BalanceChangeSwap {
+                balance_changes: HashMap::from_iter([
+                    ("usdt.tether-token.near".parse().unwrap(), -1),
+                    ("wrap.near".parse().unwrap(), 1),
+                ]),
+                pool_swaps: vec![first.clone()],
+            },
+        )
+        .await;
+    handler
+        .on_balance_change_swap(
+            context.clone(),
+            BalanceChangeSwap {
+                balance_changes: HashMap::from_iter([
+                    ("wrap.near".parse().unwrap(), -1),
+                    ("intel.tkn.near".parse().unwrap(), 1),
+                ]),
+                pool_swaps: vec![second.clone()],
+            },
+        )
+        .await;
+    assert!(handler.inner.balance_change_swaps.is_empty());
+
+    handler.flush_events(1).await;
+    assert_eq!(
+        handler.inner.balance_change_swaps.get(&trader),
+        Some(&vec![(
+            BalanceChangeSwap {
+                balance_changes: HashMap::from_iter([
+                    ("usdt.tether-token.near".parse().unwrap(), -1),
+                    ("intel.tkn.near".parse().unwrap(), 1),
+                ]),
+                pool_swaps: vec![first, second],
+            },
+            context
+        )])
+    );
+}
+
+#[test]
+fn prefixes_redis_stream_names() {
+    assert_eq!(RedisKeys::new("").stream("trade_swap"), "trade_swap");
+    assert_eq!(
+        RedisKeys::new("testnet_").stream("trade_swap"),
+        "testnet_trade_swap"
+    );
+}
+
+#[test]
+fn prefixes_all_redis_keys() {
+    let keys = RedisKeys::new("testnet_");
+    assert_eq!(keys.stream("trade_swap"), "testnet_trade_swap");
+    assert_eq!(
+        keys.volume_leaderboard(),
+        "testnet_trade_volume_by_pool_24h"
+    );
+    assert_eq!(
+        keys.volume_bucket(480_000),
+        "testnet_trade_volume_by_pool_1h:480000"
+    );
+    assert_eq!(
+        keys.protocol_fees("aidols"),
+        "testnet_trade:protocol_fees:aidols"
+    );
+    assert_eq!(
+        keys.candle("REF-5059", CandleInterval::Hour),
+        "testnet_trade:candle:1h:REF-5059"
+    );
+}
+
+#[test]
+fn combines_multiple_aidols_swaps_in_one_event() {
+    let mut buy = test_pool_swap("wrap.near", "intel.aidols.near");
+    buy.amount_in = 10;
+    buy.amount_out = 100;
+    let mut sell = test_pool_swap("intel.aidols.near", "wrap.near");
+    sell.amount_in = 40;
+    sell.amount_out = 4;
+    assert_eq!(
+        net_balance_changes(&[buy.clone(), sell]),
+        HashMap::from_iter([
+            ("wrap.near".parse().unwrap(), -6),
+            ("intel.aidols.near".parse().unwrap(), 60),
+        ])
+    );
+    let mut sell_all = test_pool_swap("intel.aidols.near", "wrap.near");
+    sell_all.amount_in = 100;
+    sell_all.amount_out = 10;
+    assert_eq!(net_balance_changes(&[buy, sell_all]), HashMap::new());
+}
+
+#[test]
+fn canonicalizes_token_account_ids() {
+    assert_eq!(
+        canonicalize_account_id("Wrap.NEAR"),
+        canonicalize_account_id("wrap.near")
+    );
+    assert_eq!(
+        canonicalize_account_id("Wrap.NEAR"),
+        Some("wrap.near".parse().unwrap())
+    );
+    assert_eq!(canonicalize_account_id("not a token"), None);
+}
+
+#[test]
+fn canonicalizes_ref_pool_token_account_ids() {
+    let pool = |token_account_ids: Vec<&str>| {
+        ref_finance_state::Pool::SimplePool(ref_finance_state::SimplePool {
+            token_account_ids: token_account_ids.into_iter().map(str::to_owned).collect(),
+            amounts: vec![0, 0],
+            volumes: vec![
+                ref_finance_state::SwapVolume {
+                    input: 0,
+                    output: 0,
+                },
+                ref_finance_state::SwapVolume {
+                    input: 0,
+                    output: 0,
+                },
+            ],
+            total_fee: 0,
+            exchange_fee: 0,
+            referral_fee: 0,
+            shares_prefix: vec![],
+            shares_total_supply: 0,
         })
+    };
+    let mut legacy = pool(vec!["Wrap.NEAR", "intel.tkn.near"]);
+    assert_eq!(legacy.canonicalize_token_account_ids(), Ok(()));
+    assert_eq!(legacy, pool(vec!["wrap.near", "intel.tkn.near"]));
+
+    let mut invalid = pool(vec!["wrap.near", "not a token"]);
+    assert_eq!(
+        invalid.canonicalize_token_account_ids(),
+        Err("not a token".to_owned())
+    );
+}
+
+#[test]
+fn detects_flash_swaps() {
+    let mut borrow = test_pool_swap("wrap.near", "usdt.tether-token.near");
+    borrow.amount_in = 1_000_000;
+    borrow.amount_out = 5_000_000;
+    let mut repay = test_pool_swap("usdt.tether-token.near", "wrap.near");
+    repay.amount_in = 5_000_000;
+    repay.amount_out = 999_500;
+    let flash_swap = BalanceChangeSwap {
+        balance_changes: HashMap::from_iter([("wrap.near".parse().unwrap(), -500)]),
+        pool_swaps: vec![borrow.clone(), repay.clone()],
+    };
+    assert!(flash_swap.is_flash_swap());
+
+    // Repays exactly what it borrowed, so no balance change is left after detection
+    let mut exact_repay = repay;
+    exact_repay.amount_out = 1_000_000;
+    let exact_flash_swap = BalanceChangeSwap {
+        balance_changes: HashMap::new(),
+        pool_swaps: vec![borrow.clone(), exact_repay],
+    };
+    assert!(exact_flash_swap.is_flash_swap());
+
+    let regular_swap = BalanceChangeSwap {
+        balance_changes: HashMap::from_iter([
+            ("wrap.near".parse().unwrap(), -1_000_000),
+            ("usdt.tether-token.near".parse().unwrap(), 5_000_000),
+        ]),
+        pool_swaps: vec![borrow],
+    };
+    assert!(!regular_swap.is_flash_swap());
+
+    // 24-decimal meme token amounts, where `change * 10_000` would overflow u128
+    let mut borrow = test_pool_swap("meek.tkn.near", "wrap.near");
+    borrow.amount_in = 10u128.pow(36);
+    borrow.amount_out = 5_000_000;
+    let mut repay = test_pool_swap("wrap.near", "meek.tkn.near");
+    repay.amount_in = 5_000_000;
+    repay.amount_out = 10u128.pow(36) - 10u128.pow(32);
+    let large_flash_swap = BalanceChangeSwap {
+        balance_changes: HashMap::from_iter([(
+            "meek.tkn.near".parse().unwrap(),
+            -(10i128.pow(32)),
+        )]),
+        pool_swaps: vec![borrow.clone(), repay],
+    };
+    assert!(large_flash_swap.is_flash_swap());
+
+    let large_regular_swap = BalanceChangeSwap {
+        balance_changes: HashMap::from_iter([
+            ("meek.tkn.near".parse().unwrap(), -(10i128.pow(36))),
+            ("wrap.near".parse().unwrap(), 5_000_000),
+        ]),
+        pool_swaps: vec![borrow],
+    };
+    assert!(!large_regular_swap.is_flash_swap());
+}
+
+#[tokio::test]
+async fn correlates_social_posts_with_swaps() {
+    let mut handler = SocialCorrelator::new(TestHandler::default(), 2);
+    let trader: AccountId = "trader.near".parse().unwrap();
+    let context_at = |block_height| TradeContext {
+        trader: trader.clone(),
+        block_height,
+        block_timestamp_nanosec: 0,
+        transaction_id: Default::default(),
+        receipt_id: Default::default(),
+    };
+    let swap = BalanceChangeSwap {
+        balance_changes: HashMap::from_iter([
+            ("wrap.near".parse().unwrap(), -1),
+            ("intel.tkn.near".parse().unwrap(), 1),
+        ]),
+        pool_swaps: vec![test_pool_swap("wrap.near", "intel.tkn.near")],
+    };
+    handler
+        .on_balance_change_swap(context_at(10), swap.clone())
+        .await;
+    handler.flush_events(10).await;
+    handler
+        .on_social_post(context_at(12), "Just bought INTEL".to_string())
+        .await;
+    handler.flush_events(12).await;
+    assert_eq!(
+        handler.inner.social_trade_posts,
+        vec![(trader.clone(), "Just bought INTEL".to_string(), swap)]
+    );
+
+    // Out of the window
+    handler
+        .on_social_post(context_at(13), "Still holding".to_string())
+        .await;
+    assert_eq!(handler.inner.social_trade_posts.len(), 1);
+}
+
+#[test]
+fn converts_ref_pool_amounts() {
+    let stable_pool = ref_finance_state::StableSwapPool {
+        token_account_ids: vec!["usdt.tether-token.near".to_owned(), "dai.near".to_owned()],
+        token_decimals: vec![6, 18],
+        c_amounts: vec![1_500_000_000_000_000_000, 2_000_000_000_000_000_000],
+        volumes: vec![],
+        total_fee: 0,
+        shares_prefix: vec![],
+        shares_total_supply: 0,
+        init_amp_factor: 0,
+        target_amp_factor: 0,
+        init_amp_time: 0,
+        stop_amp_time: 0,
+    };
+    assert_eq!(
+        stable_pool.actual_amounts(),
+        vec![1_500_000, 2_000_000_000_000_000_000]
+    );
+
+    let simple_pool = ref_finance_state::SimplePool {
+        token_account_ids: vec!["wrap.near".to_owned(), "usdt.tether-token.near".to_owned()],
+        amounts: vec![2_500_000_000_000_000_000_000_000, 1_000_000],
+        volumes: vec![],
+        total_fee: 0,
+        exchange_fee: 0,
+        referral_fee: 0,
+        shares_prefix: vec![],
+        shares_total_supply: 0,
+    };
+    let amounts = simple_pool.amounts_human(vec![24, 6]);
+    assert!((amounts[0] - 2.5).abs() < 1e-9);
+    assert_eq!(amounts[1], 1.0);
+}
+
+#[test]
+fn calculates_aidols_spot_price() {
+    let state = AidolsPoolState {
+        token_hold: 1000000000000000000000000000000000,
+        wnear_hold: 500000000000000000000000000,
+        is_deployed: false,
+        is_tradable: true,
+    };
+    assert_eq!(state.spot_price_in_wnear(), Some(500000000000000000));
+    assert_eq!(
+        state.market_cap_near(1000000000000000000000000000000000),
+        500000000000000000000000000
+    );
+
+    let empty = AidolsPoolState {
+        token_hold: 0,
+        ..state
+    };
+    assert_eq!(empty.spot_price_in_wnear(), None);
+    assert_eq!(empty.market_cap_near(1000000000000000000000000000000000), 0);
+}
+
+#[test]
+fn falls_back_to_other_aidols_pool_key_prefixes() {
+    let token_id: AccountId = "intel.aidols.near".parse().unwrap();
+    let account_bytes = borsh::to_vec(&token_id).unwrap();
+    let key = [&[0x00][..], &account_bytes].concat();
+    assert_eq!(
+        pool_key_token_id(&key, POOL_KEY_PREFIXES),
+        Some(token_id.clone())
+    );
+
+    let migrated_key = [&b"p"[..], &account_bytes].concat();
+    assert_eq!(pool_key_token_id(&migrated_key, POOL_KEY_PREFIXES), None);
+    assert_eq!(
+        pool_key_token_id(&migrated_key, &[&[0x00], b"p"]),
+        Some(token_id)
+    );
+
+    // A known prefix followed by something that isn't an account ID
+    assert_eq!(pool_key_token_id(&[0x00, 0xff], &[&[0x00], b"p"]), None);
+}
+
+#[test]
+fn coalesces_pool_changes_in_a_block() {
+    let pool_change = |pool_id: &str, receipt_id: CryptoHash| PoolChangeEvent {
+        pool_id: pool_id.to_owned(),
+        receipt_id,
+        block_timestamp_nanosec: 0,
+        block_height: 0,
+        pool: PoolType::Ref(ref_finance_state::Pool::Unknown {
+            discriminant: 3,
+            raw_bytes: vec![],
+        }),
+        spot_price: None,
+        sampled: false,
+    };
+    let first_receipt = CryptoHash([1; 32]);
+    let second_receipt = CryptoHash([2; 32]);
+    assert_eq!(
+        coalesce_pool_changes(vec![
+            pool_change("REF-1", first_receipt),
+            pool_change("REF-2", first_receipt),
+            pool_change("REF-1", second_receipt),
+        ]),
+        vec![
+            pool_change("REF-2", first_receipt),
+            pool_change("REF-1", second_receipt),
+        ]
+    );
+}
+
+#[test]
+fn splits_ref_swap_fees() {
+    let fees = ref_finance_state::PoolFees {
+        total_fee: 30,
+        exchange_fee: 5,
+        referral_fee: 1,
+    };
+    assert_eq!(
+        FeeBreakdown::from_pool_fees(1_000_000, &fees),
+        FeeBreakdown {
+            total_fee: 3_000,
+            exchange_fee: 500,
+            referral_fee: 100,
+        }
+    );
+    assert_eq!(
+        FeeBreakdown::from_pool_fees(u128::MAX / 10, &fees),
+        FeeBreakdown {
+            total_fee: 102_084_710_076_281_539_039_012_382_229_530_463,
+            exchange_fee: 17_014_118_346_046_923_173_168_730_371_588_410,
+            referral_fee: 3_402_823_669_209_384_634_633_746_074_317_682,
+        }
+    );
+}
+
+#[test]
+fn names_candle_keys() {
+    assert_eq!(
+        RedisKeys::new("").candle("REF-5059", CandleInterval::Minute),
+        "trade:candle:1m:REF-5059"
+    );
+    assert_eq!(
+        RedisKeys::new("").candle("AIDOLS-intel.aidols.near", CandleInterval::Day),
+        "trade:candle:1d:AIDOLS-intel.aidols.near"
+    );
+}
+
+#[test]
+fn interpolates_ref_amp_factor() {
+    let mut pool = ref_finance_state::StableSwapPool {
+        token_account_ids: vec!["usdt.tether-token.near".to_owned(), "dai.near".to_owned()],
+        token_decimals: vec![6, 18],
+        c_amounts: vec![0, 0],
+        volumes: vec![],
+        total_fee: 0,
+        shares_prefix: vec![],
+        shares_total_supply: 0,
+        init_amp_factor: 100,
+        target_amp_factor: 400,
+        init_amp_time: 1_000,
+        stop_amp_time: 4_000,
+    };
+    // Before the ramp
+    assert_eq!(pool.current_amp_factor(0), 100);
+    assert_eq!(pool.current_amp_factor(1_000), 100);
+    // During the ramp
+    assert_eq!(pool.current_amp_factor(2_000), 200);
+    assert_eq!(pool.current_amp_factor(2_001), 200);
+    assert_eq!(pool.current_amp_factor(3_999), 399);
+    // After the ramp
+    assert_eq!(pool.current_amp_factor(4_000), 400);
+    assert_eq!(pool.current_amp_factor(u64::MAX), 400);
+
+    // Ramping down rounds towards the initial factor too
+    pool.init_amp_factor = 400;
+    pool.target_amp_factor = 100;
+    assert_eq!(pool.current_amp_factor(2_001), 300);
+    assert_eq!(pool.current_amp_factor(4_000), 100);
+
+    // No ramp
+    pool.init_amp_time = 0;
+    pool.stop_amp_time = 0;
+    assert_eq!(pool.current_amp_factor(2_000), 100);
+
+    let rated_pool = ref_finance_state::RatedSwapPool {
+        token_account_ids: vec!["wrap.near".to_owned(), "linear-protocol.near".to_owned()],
+        token_decimals: vec![24, 24],
+        c_amounts: vec![0, 0],
+        volumes: vec![],
+        total_fee: 0,
+        shares_prefix: vec![],
+        shares_total_supply: 0,
+        init_amp_factor: 240,
+        target_amp_factor: 240,
+        init_amp_time: 1_000,
+        stop_amp_time: 4_000,
+    };
+    assert_eq!(rated_pool.current_amp_factor(2_500), 240);
+}
+
+#[test]
+fn prices_tokens_in_usd_through_wnear() {
+    let wrap_near: AccountId = "wrap.near".parse().unwrap();
+    let usdc: AccountId = "usdc.near".parse().unwrap();
+    let intel: AccountId = "intel.tkn.near".parse().unwrap();
+    let near_usd_pool = create_ref_pool_id(1);
+    let reserves = |token: &AccountId, near_amount: Balance, token_amount: Balance| {
+        HashMap::from_iter([
+            (wrap_near.clone(), near_amount),
+            (token.clone(), token_amount),
+        ])
+    };
+    let mut oracle = UsdOracle::new(
+        OracleMode::SameBlock,
+        near_usd_pool.clone(),
+        6,
+        wrap_near.clone(),
+    );
+    // 1 NEAR = 5 USDC, 1 NEAR = 1000 INTEL
+    oracle.update(
+        &near_usd_pool,
+        &reserves(&usdc, 1_000 * 10u128.pow(24), 5_000 * 10u128.pow(6)),
+    );
+    oracle.update(
+        &create_ref_pool_id(2),
+        &reserves(&intel, 10 * 10u128.pow(24), 10_000 * 10u128.pow(18)),
+    );
+    let near_value = oracle.usd_value(&wrap_near, 2 * 10u128.pow(24)).unwrap();
+    assert!((near_value - 10.0).abs() < 1e-9);
+    assert_eq!(oracle.near_usd_value(2 * 10u128.pow(24)), Some(near_value));
+    let intel_value = oracle.usd_value(&intel, 500 * 10u128.pow(18)).unwrap();
+    assert!((intel_value - 2.5).abs() < 1e-9);
+    assert_eq!(oracle.usd_value(&"unknown.near".parse().unwrap(), 1), None);
+
+    oracle.start_block();
+    assert_eq!(oracle.usd_value(&intel, 500 * 10u128.pow(18)), None);
+
+    let mut cached_oracle = UsdOracle::new(
+        OracleMode::Cached,
+        near_usd_pool.clone(),
+        6,
+        wrap_near.clone(),
+    );
+    cached_oracle.update(
+        &near_usd_pool,
+        &reserves(&usdc, 1_000 * 10u128.pow(24), 5_000 * 10u128.pow(6)),
+    );
+    cached_oracle.start_block();
+    assert!(cached_oracle
+        .usd_value(&wrap_near, 10u128.pow(24))
+        .is_some());
+}
+
+#[test]
+fn parses_bridge_destination_addresses() {
+    assert_eq!(BridgeContract::Aurora.account_id(), "aurora");
+    assert_eq!(
+        BridgeContract::Aurora.destination_address("0xAbCdEf0123456789abcdef0123456789ABCDEF01"),
+        Some("0xabcdef0123456789abcdef0123456789abcdef01".to_owned())
+    );
+    assert_eq!(
+        BridgeContract::Aurora.destination_address("abcdef0123456789abcdef0123456789abcdef01"),
+        Some("0xabcdef0123456789abcdef0123456789abcdef01".to_owned())
+    );
+    assert_eq!(BridgeContract::Aurora.destination_address("0x1234"), None);
+    assert_eq!(
+        BridgeContract::Aurora.destination_address(&"zz".repeat(20)),
+        None
+    );
+
+    let other = BridgeContract::Other {
+        account_id: "bridge.near".parse().unwrap(),
+        chain: "ethereum".to_owned(),
+    };
+    assert_eq!(other.account_id(), "bridge.near");
+    assert_eq!(other.chain(), "ethereum");
+    assert_eq!(
+        other.destination_address("recipient"),
+        Some("recipient".to_owned())
+    );
+}
+
+#[test]
+fn values_stable_pool_withdrawal_fees() {
+    assert_eq!(
+        parse_liquidity_removed_by_tokens_log(
+            "LP alice.near removed 200000000000000000000 shares by given tokens, and fee is 2000000000000000000 shares"
+        ),
+        Some((200 * 10u128.pow(18), 2 * 10u128.pow(18)))
+    );
+    assert_eq!(
+        parse_liquidity_removed_by_tokens_log(
+            "200000000000000000000 shares of liquidity removed: receive back [\"100000000 usdt.tether-token.near\"]"
+        ),
+        None
+    );
+
+    // 900 USDT and 900 DAI with 1800 shares after the withdrawal
+    let stable_pool = ref_finance_state::Pool::StableSwapPool(ref_finance_state::StableSwapPool {
+        token_account_ids: vec!["usdt.tether-token.near".to_owned(), "dai.near".to_owned()],
+        token_decimals: vec![6, 18],
+        c_amounts: vec![900 * 10u128.pow(18), 900 * 10u128.pow(18)],
+        volumes: vec![],
+        total_fee: 0,
+        shares_prefix: vec![],
+        shares_total_supply: 1_800 * 10u128.pow(18),
+        init_amp_factor: 240,
+        target_amp_factor: 240,
+        init_amp_time: 0,
+        stop_amp_time: 0,
+    });
+    assert_eq!(
+        stable_pool.share_value(2 * 10u128.pow(18)),
+        vec![
+            ("usdt.tether-token.near".parse().unwrap(), 10u128.pow(6)),
+            ("dai.near".parse().unwrap(), 10u128.pow(18)),
+        ]
+    );
+
+    let rated_pool = ref_finance_state::Pool::RatedSwapPool(ref_finance_state::RatedSwapPool {
+        token_account_ids: vec!["wrap.near".to_owned(), "linear-protocol.near".to_owned()],
+        token_decimals: vec![24, 24],
+        c_amounts: vec![3_000 * 10u128.pow(24), 1_000 * 10u128.pow(24)],
+        volumes: vec![],
+        total_fee: 0,
+        shares_prefix: vec![],
+        shares_total_supply: 4_000 * 10u128.pow(18),
+        init_amp_factor: 240,
+        target_amp_factor: 240,
+        init_amp_time: 0,
+        stop_amp_time: 0,
+    });
+    assert_eq!(
+        rated_pool.share_value(4 * 10u128.pow(18)),
+        vec![
+            ("wrap.near".parse().unwrap(), 3 * 10u128.pow(24)),
+            ("linear-protocol.near".parse().unwrap(), 10u128.pow(24)),
+        ]
+    );
+}
+
+#[test]
+fn samples_pool_changes_deterministically() {
+    let pool_change = |pool_id: &str, receipt: u8| PoolChangeEvent {
+        pool_id: pool_id.to_owned(),
+        receipt_id: CryptoHash([receipt; 32]),
+        block_timestamp_nanosec: 0,
+        block_height: 0,
+        pool: PoolType::Ref(ref_finance_state::Pool::Unknown {
+            discriminant: 0,
+            raw_bytes: vec![],
+        }),
+        spot_price: None,
+        sampled: false,
+    };
+    let events = || {
+        (0..=255)
+            .map(|receipt| pool_change("AIDOLS-intel.aidols.near", receipt))
+            .chain((0..=255).map(|receipt| pool_change("REF-1", receipt)))
+            .collect::<Vec<_>>()
+    };
+    let sampling_rate =
+        HashMap::from_iter([("AIDOLS-*".to_owned(), 0.1), ("REF-1".to_owned(), 1.0)]);
+
+    let sampled = sample_pool_changes(events(), &sampling_rate);
+    let aidols = sampled
+        .iter()
+        .filter(|event| event.pool_id.starts_with("AIDOLS-"))
+        .collect::<Vec<_>>();
+    assert!((10..50).contains(&aidols.len()), "{}", aidols.len());
+    assert!(aidols.iter().all(|event| event.sampled));
+    let ref_events = sampled
+        .iter()
+        .filter(|event| event.pool_id == "REF-1")
+        .collect::<Vec<_>>();
+    assert_eq!(ref_events.len(), 256);
+    assert!(ref_events.iter().all(|event| !event.sampled));
+
+    assert_eq!(sample_pool_changes(events(), &sampling_rate), sampled);
+}
+
+#[test]
+fn diffs_aidols_pool_states() {
+    let old = AidolsPoolState {
+        token_hold: 1_000_000,
+        wnear_hold: 500,
+        is_deployed: false,
+        is_tradable: true,
+    };
+    let new = AidolsPoolState {
+        token_hold: 900_000,
+        wnear_hold: 600,
+        is_deployed: true,
+        is_tradable: true,
+    };
+    assert_eq!(
+        AidolsPoolDiff::between(&old, &new),
+        AidolsPoolDiff {
+            token_hold_delta: -100_000,
+            wnear_hold_delta: 100,
+            is_deployed_changed: true,
+            is_tradable_changed: false,
+        }
+    );
+    assert!(AidolsPoolDiff::between(&new, &new).is_empty());
+}
+
+#[test]
+fn finds_broken_swap_chains() {
+    let arbitrage = [
+        test_pool_swap("wrap.near", "usdt.tether-token.near"),
+        test_pool_swap("usdt.tether-token.near", "intel.tkn.near"),
+        test_pool_swap("intel.tkn.near", "wrap.near"),
+    ];
+    assert!(swap_chain_breaks(&arbitrage).is_empty());
+    // Half of the wNEAR goes directly to USDT, half through USDC
+    let split_route = [
+        test_pool_swap("wrap.near", "usdt.tether-token.near"),
+        test_pool_swap("wrap.near", "usdc.near"),
+        test_pool_swap("usdc.near", "usdt.tether-token.near"),
+    ];
+    assert!(swap_chain_breaks(&split_route).is_empty());
+    let broken = [
+        test_pool_swap("wrap.near", "usdt.tether-token.near"),
+        test_pool_swap("intel.tkn.near", "wrap.near"),
+    ];
+    assert_eq!(swap_chain_breaks(&broken), vec![(0, 1)]);
+}
+
+#[test]
+fn estimates_tvl_with_the_networks_wnear() {
+    let pool = ref_finance_state::Pool::SimplePool(ref_finance_state::SimplePool {
+        token_account_ids: vec!["wrap.testnet".to_owned(), "usdc.fakes.testnet".to_owned()],
+        amounts: vec![1_000, 5_000],
+        volumes: vec![],
+        total_fee: 0,
+        exchange_fee: 0,
+        referral_fee: 0,
+        shares_prefix: vec![],
+        shares_total_supply: 0,
+    });
+    assert_eq!(estimate_tvl_near(&pool, "wrap.testnet"), Some(2_000));
+    assert_eq!(estimate_tvl_near(&pool, "wrap.near"), None);
+}
+
+#[cfg(feature = "rpc")]
+#[test]
+fn caches_token_metadata_and_failures() {
+    let usdt: AccountId = "usdt.tether-token.near".parse().unwrap();
+    let not_a_token: AccountId = "alice.near".parse().unwrap();
+    let metadata = FtMetadata {
+        symbol: "USDt".to_owned(),
+        decimals: 6,
+    };
+    let mut cache = MetadataCache::new("http://localhost:3030".to_owned());
+    assert!(cache.should_fetch(&usdt));
+    assert_eq!(
+        cache.insert_result(&usdt, Ok(metadata.clone())),
+        Some(metadata.clone())
+    );
+    assert_eq!(cache.get(&usdt), Some(&metadata));
+    assert!(!cache.should_fetch(&usdt));
+    // A concurrent call for the same token that finished later isn't reported again
+    assert_eq!(cache.insert_result(&usdt, Ok(metadata.clone())), None);
+
+    assert_eq!(
+        cache.insert_result(&not_a_token, Err("MethodNotFound".to_owned())),
+        None
+    );
+    assert_eq!(cache.get(&not_a_token), None);
+    assert!(!cache.should_fetch(&not_a_token));
+    let cache = cache.with_retry_after(std::time::Duration::ZERO);
+    assert!(cache.should_fetch(&not_a_token));
+    assert!(!cache.should_fetch(&usdt));
+}
+
+struct RejectTokens(Vec<AccountId>);
+
+#[async_trait]
+impl TokenValidator for RejectTokens {
+    async fn is_valid_token(&self, token: &AccountId) -> bool {
+        !self.0.contains(token)
+    }
+}
+
+#[tokio::test]
+async fn skips_pools_with_rejected_tokens() {
+    let validator = RejectTokens(vec!["spam.near".parse().unwrap()]);
+    let simple_pool = |tokens: [&str; 2]| {
+        ref_finance_state::Pool::SimplePool(ref_finance_state::SimplePool {
+            token_account_ids: tokens.map(str::to_owned).to_vec(),
+            amounts: vec![1_000, 5_000],
+            volumes: vec![],
+            total_fee: 0,
+            exchange_fee: 0,
+            referral_fee: 0,
+            shares_prefix: vec![],
+            shares_total_supply: 0,
+        })
+    };
+    assert_eq!(
+        invalid_pool_token(&validator, &simple_pool(["wrap.near", "spam.near"])).await,
+        Some("spam.near".parse().unwrap())
+    );
+    assert_eq!(
+        invalid_pool_token(
+            &validator,
+            &simple_pool(["wrap.near", "usdt.tether-token.near"])
+        )
+        .await,
+        None
     );
 }
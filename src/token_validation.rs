@@ -0,0 +1,44 @@
+#[cfg(feature = "rpc")]
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use inindexer::near_indexer_primitives::types::AccountId;
+#[cfg(feature = "rpc")]
+use tokio::sync::RwLock;
+
+#[cfg(feature = "rpc")]
+use crate::metadata_cache::MetadataCache;
+
+/// Decides whether a token in a Ref pool is a real fungible token, see
+/// [`crate::TradeIndexer::with_token_validator`]
+#[async_trait]
+pub trait TokenValidator: Send + Sync + 'static {
+    async fn is_valid_token(&self, token: &AccountId) -> bool;
+}
+
+/// Treats a token as valid if its contract responds to `ft_metadata`. Valid tokens
+/// are cached, invalid ones are rejected without a call until
+/// [`crate::metadata_cache::METADATA_RETRY_AFTER`] passes, then checked again in
+/// case the call failed because of the RPC.
+#[cfg(feature = "rpc")]
+pub struct NearRpcTokenValidator {
+    metadata_cache: Arc<RwLock<MetadataCache>>,
+}
+
+#[cfg(feature = "rpc")]
+impl NearRpcTokenValidator {
+    pub fn new(rpc_url: String) -> Self {
+        Self {
+            metadata_cache: Arc::new(RwLock::new(MetadataCache::new(rpc_url))),
+        }
+    }
+}
+
+#[cfg(feature = "rpc")]
+#[async_trait]
+impl TokenValidator for NearRpcTokenValidator {
+    async fn is_valid_token(&self, token: &AccountId) -> bool {
+        MetadataCache::fetch_if_new(&self.metadata_cache, token).await;
+        self.metadata_cache.read().await.get(token).is_some()
+    }
+}
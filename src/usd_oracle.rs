@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+
+use inindexer::near_indexer_primitives::types::{AccountId, Balance};
+
+use crate::PoolId;
+
+/// Which prices [`crate::TradeIndexer::with_usd_oracle`] uses
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OracleMode {
+    /// Only prices from pool changes in the same block as the swap, tokens whose
+    /// wNEAR pool didn't change in the block have no USD amounts
+    SameBlock,
+    /// Last known price of each token, no matter how old it is
+    Cached,
+}
+
+/// USD prices of tokens derived from reserves of Ref simple pools paired with
+/// wNEAR, and a wNEAR / USD stablecoin pool
+pub(crate) struct UsdOracle {
+    mode: OracleMode,
+    near_usd_pool: PoolId,
+    usd_token_decimals: u8,
+    wrap_near: AccountId,
+    /// USD per raw unit of wNEAR
+    usd_per_near: Option<f64>,
+    /// Raw units of wNEAR per raw unit of each token
+    near_prices: HashMap<AccountId, f64>,
+}
+
+impl UsdOracle {
+    pub fn new(
+        mode: OracleMode,
+        near_usd_pool: PoolId,
+        usd_token_decimals: u8,
+        wrap_near: AccountId,
+    ) -> Self {
+        Self {
+            mode,
+            near_usd_pool,
+            usd_token_decimals,
+            wrap_near,
+            usd_per_near: None,
+            near_prices: HashMap::new(),
+        }
+    }
+
+    pub fn start_block(&mut self) {
+        if self.mode == OracleMode::SameBlock {
+            self.usd_per_near = None;
+            self.near_prices.clear();
+        }
+    }
+
+    /// Updates prices with new reserves of a pool. Only 2-token pools with wNEAR
+    /// are used, a token's price comes from whichever of its pools changed last.
+    pub fn update(&mut self, pool_id: &PoolId, reserves: &HashMap<AccountId, Balance>) {
+        if reserves.len() != 2 {
+            return;
+        }
+        let Some(&near_reserve) = reserves.get(&self.wrap_near) else {
+            return;
+        };
+        let Some((token, &token_reserve)) =
+            reserves.iter().find(|(token, _)| **token != self.wrap_near)
+        else {
+            return;
+        };
+        if near_reserve == 0 || token_reserve == 0 {
+            return;
+        }
+        if *pool_id == self.near_usd_pool {
+            self.usd_per_near = Some(
+                token_reserve as f64
+                    / 10f64.powi(self.usd_token_decimals as i32)
+                    / near_reserve as f64,
+            );
+        }
+        self.near_prices
+            .insert(token.clone(), near_reserve as f64 / token_reserve as f64);
+    }
+
+    /// Value of a raw wNEAR amount in USD
+    pub fn near_usd_value(&self, amount: Balance) -> Option<f64> {
+        self.usd_value(&self.wrap_near, amount)
+    }
+
+    /// Value of a raw token amount in USD, through the token's wNEAR price
+    pub fn usd_value(&self, token: &AccountId, amount: Balance) -> Option<f64> {
+        let usd_per_near = self.usd_per_near?;
+        let near_per_token = if *token == self.wrap_near {
+            1.0
+        } else {
+            *self.near_prices.get(token)?
+        };
+        Some(amount as f64 * near_per_token * usd_per_near)
+    }
+}